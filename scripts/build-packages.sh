@@ -59,6 +59,7 @@ mkdir -p "$TARBALL_DIR"/{usr/bin,lib/systemd/system,usr/share/doc/linux-bt-proxy
 # Copy files
 cp target/release/linux_bt_proxy "$TARBALL_DIR/usr/bin/"
 cp systemd/linux-bt-proxy.service "$TARBALL_DIR/lib/systemd/system/"
+cp systemd/linux-bt-proxy.socket "$TARBALL_DIR/lib/systemd/system/"
 cp README.rst "$TARBALL_DIR/usr/share/doc/linux-bt-proxy/"
 cp LICENSE "$TARBALL_DIR/usr/share/doc/linux-bt-proxy/"
 
@@ -79,8 +80,8 @@ fi
 cp usr/bin/linux_bt_proxy /usr/bin/
 chmod 755 /usr/bin/linux_bt_proxy
 
-cp lib/systemd/system/linux-bt-proxy.service /lib/systemd/system/
-chmod 644 /lib/systemd/system/linux-bt-proxy.service
+cp lib/systemd/system/linux-bt-proxy.service lib/systemd/system/linux-bt-proxy.socket /lib/systemd/system/
+chmod 644 /lib/systemd/system/linux-bt-proxy.service /lib/systemd/system/linux-bt-proxy.socket
 
 # Copy documentation
 mkdir -p /usr/share/doc/linux-bt-proxy
@@ -110,12 +111,12 @@ if [[ $EUID -ne 0 ]]; then
 fi
 
 # Stop and disable service
-systemctl stop linux-bt-proxy 2>/dev/null || true
-systemctl disable linux-bt-proxy 2>/dev/null || true
+systemctl stop linux-bt-proxy linux-bt-proxy.socket 2>/dev/null || true
+systemctl disable linux-bt-proxy linux-bt-proxy.socket 2>/dev/null || true
 
 # Remove files
 rm -f /usr/bin/linux_bt_proxy
-rm -f /lib/systemd/system/linux-bt-proxy.service
+rm -f /lib/systemd/system/linux-bt-proxy.service /lib/systemd/system/linux-bt-proxy.socket
 rm -rf /usr/share/doc/linux-bt-proxy
 
 # Reload systemd
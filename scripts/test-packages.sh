@@ -26,6 +26,7 @@ fi
 echo "Testing required files..."
 required_files=(
     "systemd/linux-bt-proxy.service"
+    "systemd/linux-bt-proxy.socket"
     "README.rst"
     "LICENSE"
     "Cargo.toml"
@@ -1,20 +1,110 @@
 use futures_util::stream::StreamExt;
 use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use tokio::sync::broadcast::Sender;
+use tokio::sync::{mpsc, watch};
 
-use zbus::fdo::PropertiesProxy;
+use zbus::fdo::{ObjectManager, PropertiesProxy};
 use zbus::match_rule::MatchRule;
 use zbus::names::InterfaceName;
-use zbus::zvariant::{Dict, ObjectPath, OwnedValue};
+use zbus::zvariant::{Dict, ObjectPath, OwnedObjectPath, OwnedValue};
 use zbus::{message::Type, Connection, MessageStream, Proxy};
 
 use crate::api::api::{BluetoothLEAdvertisementResponse, BluetoothServiceData};
+use crate::rules::ScanOverrides;
+use crate::tracker::{DeviceTracker, DEVICE_TIMEOUT};
+use crate::utils::{mac_to_address, parse_mac};
+
+/// Requests for the listener, which owns the BlueZ discovery session
+/// (BlueZ only lets the D-Bus client that started discovery stop it).
+#[derive(Debug, Clone, Copy)]
+pub enum BleCommand {
+    RestartDiscovery,
+    PurgeStaleDevices,
+    /// Stop scanning and end the listener
+    Shutdown,
+}
+
+/// Counters for the metrics endpoint.
+#[derive(Default)]
+pub struct BleStats {
+    /// BlueZ calls that failed without ending the listener
+    pub dbus_errors: AtomicU64,
+    /// Discovery restarted, by request or after BlueZ turned it off
+    pub discovery_restarts: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanMode {
+    /// Scan requests for every device, for names and scan response data
+    Active,
+    /// Only listen, which saves the devices' batteries
+    Passive,
+}
+
+impl ScanMode {
+    pub const LABELS: &'static [&'static str] = &["Active", "Passive"];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ScanMode::Active => "Active",
+            ScanMode::Passive => "Passive",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<Self> {
+        match label {
+            "Active" => Some(ScanMode::Active),
+            "Passive" => Some(ScanMode::Passive),
+            _ => None,
+        }
+    }
+}
+
+/// How the adapter should scan; changed from Home Assistant and kept in
+/// the state directory. Rules can override it for a while.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanSettings {
+    pub enabled: bool,
+    pub mode: ScanMode,
+    #[serde(skip)]
+    pub overrides: ScanOverrides,
+}
+
+impl Default for ScanSettings {
+    fn default() -> Self {
+        ScanSettings {
+            enabled: true,
+            mode: ScanMode::Active,
+            overrides: ScanOverrides::default(),
+        }
+    }
+}
+
+impl ScanSettings {
+    /// Whether to scan, rules taken into account.
+    pub fn is_scanning(&self) -> bool {
+        self.overrides.enabled.unwrap_or(self.enabled)
+    }
+
+    /// Mode to scan in, rules taken into account.
+    pub fn effective_mode(&self) -> ScanMode {
+        self.overrides.mode.unwrap_or(self.mode)
+    }
+}
 
 pub async fn run_bluez_advertisement_listener(
     adapter_index: u16,
     tx: Sender<BluetoothLEAdvertisementResponse>,
+    tracker: Arc<DeviceTracker>,
+    mut commands: mpsc::Receiver<BleCommand>,
+    mut settings: watch::Receiver<ScanSettings>,
+    stats: Arc<BleStats>,
 ) -> zbus::Result<()> {
     let conn = Connection::system().await?;
     let adapter_rule = MatchRule::builder()
@@ -41,11 +131,34 @@ pub async fn run_bluez_advertisement_listener(
     let mut props_stream = MessageStream::for_match_rule(props_rule, &conn, None).await?;
     let mut iface_stream = MessageStream::for_match_rule(iface_rule, &conn, None).await?;
 
-    // All streams ready: now start discovery
-    try_start_discovery(&conn, adapter_index).await?;
+    // All streams ready: now start scanning
+    let mut scanner = Scanner {
+        conn: &conn,
+        adapter_index,
+        running: None,
+        stats: &stats,
+    };
+    let initial = *settings.borrow_and_update();
+    scanner.apply(initial).await?;
 
     loop {
         tokio::select! {
+            Some(command) = commands.recv() => {
+                run_command(&mut scanner, &tracker, command).await;
+                if let BleCommand::Shutdown = command {
+                    info!("Stopped scanning on hci{adapter_index}");
+                    return Ok(());
+                }
+            }
+
+            Ok(()) = settings.changed() => {
+                let wanted = *settings.borrow_and_update();
+                if let Err(e) = scanner.apply(wanted).await {
+                    stats.dbus_errors.fetch_add(1, Ordering::Relaxed);
+                    warn!("Failed to apply scan settings {wanted:?} on hci{adapter_index}: {e}");
+                }
+            }
+
             maybe_msg = adapter_stream.next() => {
                 if let Some(Ok(msg)) = maybe_msg {
                     let body = msg.body();
@@ -55,8 +168,10 @@ pub async fn run_bluez_advertisement_listener(
                     if interface == "org.bluez.Adapter1" {
                         if let Some(value) = changed.get("Discovering") {
                             if let Ok(is_discovering) = value.downcast_ref::<bool>() {
-                                if !is_discovering {
+                                // Unless we stopped it ourselves
+                                if !is_discovering && scanner.running == Some(ScanMode::Active) {
                                     info!("Discovery was turned off — restarting discovery.");
+                                    stats.discovery_restarts.fetch_add(1, Ordering::Relaxed);
                                     try_start_discovery(&conn, adapter_index).await?;
                                 }
                             }
@@ -87,6 +202,7 @@ pub async fn run_bluez_advertisement_listener(
                                 };
                                 }
                             Err(e) => {
+                                stats.dbus_errors.fetch_add(1, Ordering::Relaxed);
                                 warn!("Failed to fetch properties for {path}: {e}");
                             }
                         }
@@ -128,7 +244,7 @@ pub async fn run_bluez_advertisement_listener(
             }
         } // select
     } // loop
-      // Note: This function will run indefinitely, listening for advertisements.
+      // Note: This function runs until told to shut down, listening for advertisements.
 }
 
 fn build_advertisement_response(
@@ -166,8 +282,15 @@ fn build_advertisement_response(
         extract_service_data(props.get("ManufacturerData"), false).unwrap_or_default();
 
     if let Some(mac_str) = mac_opt {
+        let mac = match parse_mac(&mac_str) {
+            Ok(mac) => mac,
+            Err(e) => {
+                debug!("Ignoring device with address '{mac_str}': {e}");
+                return None;
+            }
+        };
         let msg = BluetoothLEAdvertisementResponse {
-            address: parse_ble_address(&mac_str),
+            address: mac_to_address(mac),
             address_type,
             name,
             rssi,
@@ -200,15 +323,22 @@ fn print_props(props: &HashMap<String, OwnedValue>) {
     }
 }
 
-async fn try_start_discovery(conn: &Connection, adapter_index: u16) -> zbus::Result<()> {
-    let adapter_path = format!("/org/bluez/hci{adapter_index}");
-    let proxy = Proxy::new(
+fn adapter_path(adapter_index: u16) -> String {
+    format!("/org/bluez/hci{adapter_index}")
+}
+
+async fn adapter_proxy(conn: &Connection, adapter_index: u16) -> zbus::Result<Proxy<'static>> {
+    Proxy::new(
         conn,
         "org.bluez",
-        ObjectPath::try_from(adapter_path.as_str())?,
+        ObjectPath::try_from(adapter_path(adapter_index))?,
         "org.bluez.Adapter1",
     )
-    .await?;
+    .await
+}
+
+async fn try_start_discovery(conn: &Connection, adapter_index: u16) -> zbus::Result<()> {
+    let proxy = adapter_proxy(conn, adapter_index).await?;
 
     proxy
         .call_method("SetDiscoveryFilter", &(HashMap::<&str, OwnedValue>::new()))
@@ -226,10 +356,245 @@ async fn try_start_discovery(conn: &Connection, adapter_index: u16) -> zbus::Res
     Ok(())
 }
 
-fn parse_ble_address(address: &str) -> u64 {
-    address.split(':').fold(0, |acc, part| {
-        (acc << 8) | u8::from_str_radix(part, 16).unwrap_or(0) as u64
-    })
+async fn stop_discovery(conn: &Connection, adapter_index: u16) -> zbus::Result<()> {
+    let proxy = adapter_proxy(conn, adapter_index).await?;
+    match proxy.call_method("StopDiscovery", &()).await {
+        Ok(_) => info!("Discovery stopped"),
+        // Not ours to stop, or already stopped
+        Err(zbus::Error::MethodError(ref name, _, _))
+            if name.as_str() == "org.bluez.Error.Failed"
+                || name.as_str() == "org.bluez.Error.NotReady" =>
+        {
+            debug!("StopDiscovery: {name}");
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+// Where the passive scanning monitor is served; BlueZ asks the object
+// manager here for the monitors under it
+const MONITOR_APP_PATH: &str = "/org/linux_bt_proxy/monitor";
+
+// Advertising flags values matched in passive mode: the same patterns Home
+// Assistant's own passive scanner uses, covering nearly every advertiser
+const PASSIVE_SCAN_FLAGS: &[u8] = &[0x02, 0x06, 0x1a];
+const AD_TYPE_FLAGS: u8 = 0x01;
+
+/// BlueZ has no passive discovery; passive scanning is an advertisement
+/// monitor instead. Matching devices show up as `Device1` objects just as
+/// with discovery, so the listener doesn't need to know which is running.
+struct PassiveMonitor;
+
+#[zbus::interface(name = "org.bluez.AdvertisementMonitor1")]
+impl PassiveMonitor {
+    fn release(&self) {
+        debug!("Advertisement monitor released");
+    }
+
+    fn activate(&self) {
+        info!("Passive scanning started");
+    }
+
+    fn device_found(&self, _device: ObjectPath<'_>) {}
+
+    fn device_lost(&self, _device: ObjectPath<'_>) {}
+
+    #[zbus(property, name = "Type")]
+    fn monitor_type(&self) -> &str {
+        "or_patterns"
+    }
+
+    #[zbus(property)]
+    fn patterns(&self) -> Vec<(u8, u8, Vec<u8>)> {
+        PASSIVE_SCAN_FLAGS
+            .iter()
+            .map(|&flags| (0, AD_TYPE_FLAGS, vec![flags]))
+            .collect()
+    }
+}
+
+async fn monitor_manager_proxy(
+    conn: &Connection,
+    adapter_index: u16,
+) -> zbus::Result<Proxy<'static>> {
+    Proxy::new(
+        conn,
+        "org.bluez",
+        ObjectPath::try_from(adapter_path(adapter_index))?,
+        "org.bluez.AdvertisementMonitorManager1",
+    )
+    .await
+}
+
+async fn start_passive_scan(conn: &Connection, adapter_index: u16) -> zbus::Result<()> {
+    // Serving the objects again after a restart is a no-op
+    let server = conn.object_server();
+    server.at(MONITOR_APP_PATH, ObjectManager).await?;
+    server
+        .at(format!("{MONITOR_APP_PATH}/0"), PassiveMonitor)
+        .await?;
+    monitor_manager_proxy(conn, adapter_index)
+        .await?
+        .call_method("RegisterMonitor", &ObjectPath::try_from(MONITOR_APP_PATH)?)
+        .await?;
+    Ok(())
+}
+
+async fn stop_passive_scan(conn: &Connection, adapter_index: u16) -> zbus::Result<()> {
+    monitor_manager_proxy(conn, adapter_index)
+        .await?
+        .call_method(
+            "UnregisterMonitor",
+            &ObjectPath::try_from(MONITOR_APP_PATH)?,
+        )
+        .await?;
+    info!("Passive scanning stopped");
+    Ok(())
+}
+
+/// The scan the listener holds open with BlueZ, if any.
+struct Scanner<'a> {
+    conn: &'a Connection,
+    adapter_index: u16,
+    running: Option<ScanMode>,
+    stats: &'a BleStats,
+}
+
+impl Scanner<'_> {
+    /// Starts, stops or switches scanning to match `settings`.
+    async fn apply(&mut self, settings: ScanSettings) -> zbus::Result<()> {
+        let wanted = settings.is_scanning().then_some(settings.effective_mode());
+        if wanted == self.running {
+            return Ok(());
+        }
+        self.stop().await?;
+        match wanted {
+            Some(mode) => self.start(mode).await,
+            None => {
+                info!("Scanning disabled on hci{}", self.adapter_index);
+                Ok(())
+            }
+        }
+    }
+
+    async fn start(&mut self, mode: ScanMode) -> zbus::Result<()> {
+        match mode {
+            ScanMode::Active => try_start_discovery(self.conn, self.adapter_index).await?,
+            ScanMode::Passive => start_passive_scan(self.conn, self.adapter_index).await?,
+        }
+        self.running = Some(mode);
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> zbus::Result<()> {
+        match self.running {
+            Some(ScanMode::Active) => stop_discovery(self.conn, self.adapter_index).await?,
+            Some(ScanMode::Passive) => stop_passive_scan(self.conn, self.adapter_index).await?,
+            None => {}
+        }
+        self.running = None;
+        Ok(())
+    }
+
+    /// Stops and starts whatever is running, for a scanner that's stuck
+    /// without having told us.
+    async fn restart(&mut self) -> zbus::Result<()> {
+        let Some(mode) = self.running else {
+            info!("Scanning is disabled, nothing to restart");
+            return Ok(());
+        };
+        info!(
+            "Restarting {} scanning on hci{}",
+            mode.label().to_lowercase(),
+            self.adapter_index
+        );
+        self.stats
+            .discovery_restarts
+            .fetch_add(1, Ordering::Relaxed);
+        self.stop().await?;
+        self.start(mode).await
+    }
+}
+
+/// Failures are logged rather than ending the listener: the adapter is
+/// still scanning (or restarts itself) whatever became of the request.
+async fn run_command(scanner: &mut Scanner<'_>, tracker: &DeviceTracker, command: BleCommand) {
+    let result = match command {
+        BleCommand::RestartDiscovery => scanner.restart().await,
+        BleCommand::PurgeStaleDevices => {
+            purge_stale_devices(scanner.conn, scanner.adapter_index, tracker).await
+        }
+        BleCommand::Shutdown => scanner.stop().await,
+    };
+    if let Err(e) = result {
+        scanner.stats.dbus_errors.fetch_add(1, Ordering::Relaxed);
+        warn!("{command:?} failed on hci{}: {e}", scanner.adapter_index);
+    }
+}
+
+/// Removes devices BlueZ still lists but that haven't advertised for
+/// `DEVICE_TIMEOUT`; with a busy neighbourhood these pile up into the
+/// thousands and slow bluetoothd down. Paired, trusted and connected
+/// devices are kept.
+async fn purge_stale_devices(
+    conn: &Connection,
+    adapter_index: u16,
+    tracker: &DeviceTracker,
+) -> zbus::Result<()> {
+    if tracker.running_for() < DEVICE_TIMEOUT {
+        warn!(
+            "Not purging BlueZ devices until we've listened for {}s",
+            DEVICE_TIMEOUT.as_secs()
+        );
+        return Ok(());
+    }
+    let object_manager =
+        Proxy::new(conn, "org.bluez", "/", "org.freedesktop.DBus.ObjectManager").await?;
+    let objects: HashMap<OwnedObjectPath, HashMap<String, HashMap<String, OwnedValue>>> =
+        object_manager.call("GetManagedObjects", &()).await?;
+
+    let adapter = adapter_proxy(conn, adapter_index).await?;
+    let adapter_prefix = format!("{}/", adapter.path());
+    let flag = |props: &HashMap<String, OwnedValue>, name: &str| {
+        props
+            .get(name)
+            .and_then(|v| v.downcast_ref::<bool>().ok())
+            .unwrap_or(false)
+    };
+    let (mut removed, mut kept) = (0, 0);
+    for (path, interfaces) in &objects {
+        let Some(props) = interfaces.get("org.bluez.Device1") else {
+            continue;
+        };
+        if !path.as_str().starts_with(&adapter_prefix) {
+            continue;
+        }
+        let address = props
+            .get("Address")
+            .and_then(|v| v.downcast_ref::<String>().ok())
+            .and_then(|a| match parse_mac(&a) {
+                Ok(mac) => Some(mac_to_address(mac)),
+                Err(e) => {
+                    debug!("Keeping {path}, its address '{a}' is invalid: {e}");
+                    None
+                }
+            });
+        let stale = address.is_some_and(|a| !tracker.seen_recently(a))
+            && !flag(props, "Paired")
+            && !flag(props, "Trusted")
+            && !flag(props, "Connected");
+        if !stale {
+            kept += 1;
+            continue;
+        }
+        match adapter.call_method("RemoveDevice", path).await {
+            Ok(_) => removed += 1,
+            Err(e) => debug!("RemoveDevice {path}: {e}"),
+        }
+    }
+    info!("Purged {removed} stale devices from BlueZ on hci{adapter_index}, kept {kept}");
+    Ok(())
 }
 
 fn extract_service_data(
@@ -0,0 +1,333 @@
+use anyhow::{anyhow, Context, Result};
+use log::{error, info, warn, LevelFilter};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::context::ProxyContext;
+use crate::host::HostSensor;
+use crate::logs;
+use crate::rules::Rule;
+use crate::services::Service;
+use crate::utils::parse_mac;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Mac(pub [u8; 6]);
+
+impl TryFrom<String> for Mac {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        parse_mac(&text).map(Mac)
+    }
+}
+
+/// The configuration file, with the same settings as the command line
+/// options of the same name (with `_` for `-`). All are optional, and
+/// options given on the command line win.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub hci: Option<u16>,
+    pub listen: Option<SocketAddr>,
+    pub http_listen: Option<SocketAddr>,
+    pub hostname: Option<String>,
+    pub mac: Option<Mac>,
+    pub encryption_key_file: Option<PathBuf>,
+    pub plaintext: Option<bool>,
+    pub password_file: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+    #[serde(deserialize_with = "at_least_one")]
+    pub keepalive_interval: Option<u64>,
+    #[serde(deserialize_with = "at_least_one")]
+    pub keepalive_misses: Option<u32>,
+    pub max_clock_skew: Option<u64>,
+    pub host_sensors: Option<Vec<HostSensor>>,
+    pub watch: Option<Vec<Mac>>,
+    #[serde(deserialize_with = "watch_timeout")]
+    pub watch_timeout: Option<u64>,
+    pub min_rssi: Option<i32>,
+    pub rules: Option<Vec<Rule>>,
+    pub services: Option<Vec<Service>>,
+    /// Filter in `RUST_LOG` syntax; `RUST_LOG` itself wins
+    #[serde(deserialize_with = "log_filter")]
+    pub log_level: Option<String>,
+    /// The file as read, to tell what a reload changes
+    #[serde(skip)]
+    table: toml::Table,
+}
+
+fn in_range<'de, D, T>(deserializer: D, range: RangeInclusive<T>) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + PartialOrd + Display,
+{
+    let value = T::deserialize(deserializer)?;
+    if !range.contains(&value) {
+        return Err(D::Error::custom(format!(
+            "expected a value from {} to {}, got {value}",
+            range.start(),
+            range.end()
+        )));
+    }
+    Ok(Some(value))
+}
+
+fn at_least_one<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + PartialOrd + Display + TryFrom<u8>,
+{
+    let value = T::deserialize(deserializer)?;
+    if T::try_from(1).is_ok_and(|one| value < one) {
+        return Err(D::Error::custom(format!(
+            "expected at least 1, got {value}"
+        )));
+    }
+    Ok(Some(value))
+}
+
+/// As long as the proxy remembers devices.
+fn watch_timeout<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    in_range(deserializer, 1..=300)
+}
+
+/// env_logger skips directives it can't parse with just a warning on
+/// stderr; refuse them here instead.
+fn log_filter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let filter = String::deserialize(deserializer)?;
+    let directives = filter.split('/').next().unwrap_or_default();
+    for directive in directives.split(',').map(str::trim) {
+        let level = match directive.split_once('=') {
+            Some((_, level)) => level,
+            // A bare word is a level if it parses as one, a module otherwise
+            None => continue,
+        };
+        if level.parse::<LevelFilter>().is_err() {
+            return Err(D::Error::custom(format!(
+                "unknown log level '{level}' (expected off, error, warn, info, debug or trace)"
+            )));
+        }
+    }
+    Ok(Some(filter))
+}
+
+impl Config {
+    /// Reads and checks the whole file; errors point at the offending line
+    /// and column.
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration from {}", path.display()))?;
+        text.parse()
+            .map_err(|e| anyhow!("Invalid configuration in {}: {e}", path.display()))
+    }
+
+    /// Settings that differ in `other`, including ones added or removed.
+    fn changed<'a>(&'a self, other: &'a Config) -> BTreeSet<&'a str> {
+        self.table
+            .keys()
+            .chain(other.table.keys())
+            .filter(|key| self.table.get(*key) != other.table.get(*key))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+impl FromStr for Config {
+    type Err = toml::de::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut config: Config = toml::from_str(text)?;
+        if config.plaintext == Some(true) && config.encryption_key_file.is_some() {
+            return Err(toml::de::Error::custom(
+                "plaintext and encryption_key_file can't both be set",
+            ));
+        }
+        config.table = text.parse().expect("parsed above");
+        Ok(config)
+    }
+}
+
+/// Rereads the configuration file on SIGHUP. Settings that can change
+/// without disturbing clients (the log level and RSSI filter) apply right
+/// away; the others, rules included, are reported as needing a restart. `overridden` are settings
+/// given on the command line, which a reload leaves alone.
+pub async fn run_reload(
+    ctx: Arc<ProxyContext>,
+    path: Option<PathBuf>,
+    mut current: Config,
+    overridden: HashSet<&'static str>,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Cannot reload the configuration on SIGHUP: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let Some(path) = &path else {
+            warn!("Got SIGHUP, but there's no configuration file to reload (see --config)");
+            continue;
+        };
+        let config = match Config::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{e:#}");
+                error!("Keeping the current configuration");
+                continue;
+            }
+        };
+
+        let changed = current.changed(&config);
+        if changed.is_empty() {
+            info!("Reloaded {}, nothing changed", path.display());
+        }
+        for &key in &changed {
+            match key {
+                _ if overridden.contains(key) => {
+                    info!("{key} changed, but is set on the command line, which wins")
+                }
+                "log_level" => match config.log_level.as_deref() {
+                    Some(filter) => {
+                        logs::set_filter(Some(filter));
+                        info!("Log level now {filter}");
+                    }
+                    None => {
+                        // Said before going back, which may well hide it
+                        match std::env::var("RUST_LOG") {
+                            Ok(filter) => info!("Log level now {filter}, from RUST_LOG"),
+                            Err(_) => info!("Log level now error, env_logger's default"),
+                        }
+                        logs::set_filter(None);
+                    }
+                },
+                "min_rssi" => {
+                    *ctx.min_rssi.write().unwrap() = config.min_rssi;
+                    match config.min_rssi {
+                        Some(min_rssi) => info!("Minimum RSSI now {min_rssi} dBm"),
+                        None => info!("Minimum RSSI removed"),
+                    }
+                }
+                _ => warn!("{key} changed; restart to apply it"),
+            }
+        }
+        current = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        text.parse::<Config>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_a_full_file() {
+        let config: Config = r#"
+            hci = 1
+            listen = "[::]:6054"
+            mac = "02:00:00:00:00:01"
+            plaintext = true
+            keepalive_interval = 30
+            host_sensors = ["cpu-temperature", "bluetoothd"]
+            watch = ["AA:BB:CC:DD:EE:01"]
+            watch_timeout = 300
+            min_rssi = -85
+            rules = ["media_player.tv=playing;mode=passive"]
+            services = ["reset_adapter=hciconfig hci0 reset"]
+            log_level = "info,linux_bt_proxy=debug"
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.hci, Some(1));
+        assert_eq!(config.listen, Some("[::]:6054".parse().unwrap()));
+        assert_eq!(config.mac.map(|mac| mac.0), Some([2, 0, 0, 0, 0, 1]));
+        assert_eq!(
+            config.host_sensors,
+            Some(vec![HostSensor::CpuTemperature, HostSensor::Bluetoothd])
+        );
+        assert_eq!(config.watch_timeout, Some(300));
+        assert_eq!(config.min_rssi, Some(-85));
+        assert_eq!(config.rules.map(|rules| rules.len()), Some(1));
+        assert_eq!(
+            config.services.map(|services| services[0].command.len()),
+            Some(3)
+        );
+        assert_eq!(config.password_file, None);
+    }
+
+    #[test]
+    fn errors_point_at_line_and_column() {
+        let e = error("hci = 0\nlisten_on = \"[::]:6053\"\n");
+        assert!(e.contains("line 2, column 1"), "{e}");
+        assert!(e.contains("unknown field `listen_on`"), "{e}");
+
+        let e = error("hci = 0\n\nwatch_timeout = 301\n");
+        assert!(e.contains("line 3, column 17"), "{e}");
+        assert!(e.contains("expected a value from 1 to 300, got 301"), "{e}");
+
+        let e = error("keepalive_misses = 0\n");
+        assert!(e.contains("line 1, column 20"), "{e}");
+        assert!(e.contains("expected at least 1, got 0"), "{e}");
+
+        let e = error("log_level = \"info,ble=loud\"\n");
+        assert!(e.contains("line 1, column 13"), "{e}");
+        assert!(e.contains("unknown log level 'loud'"), "{e}");
+
+        let e = error("rules = [\n  \"sun.sun=up;mode=passive\",\n  \"sun.sun=down\",\n]\n");
+        // toml points at the array rather than the element
+        assert!(e.contains("line 1, column 9"), "{e}");
+        assert!(e.contains("expected ENTITY=STATE;SETTING=VALUE"), "{e}");
+
+        let e = error("mac = \"02:00:00\"\n");
+        assert!(e.contains("line 1, column 7"), "{e}");
+        assert!(e.contains("Invalid MAC format"), "{e}");
+    }
+
+    #[test]
+    fn plaintext_excludes_a_key() {
+        let e = error("plaintext = true\nencryption_key_file = \"/etc/api.key\"\n");
+        assert!(e.contains("can't both be set"), "{e}");
+
+        let config: Config = "plaintext = false\nencryption_key_file = \"/etc/api.key\""
+            .parse()
+            .unwrap();
+        assert!(config.encryption_key_file.is_some());
+    }
+
+    #[test]
+    fn log_levels_may_name_modules() {
+        for filter in ["debug", "linux_bt_proxy", "info,zbus=warn", "warn/scanner"] {
+            let config: Config = format!("log_level = {filter:?}").parse().unwrap();
+            assert_eq!(config.log_level.as_deref(), Some(filter));
+        }
+    }
+
+    #[test]
+    fn reports_changed_settings() {
+        let before: Config =
+            "hci = 0\nmin_rssi = -80\nrules = [\"a.b=c;mode=passive\"]\nplaintext = true"
+                .parse()
+                .unwrap();
+        let after: Config =
+            "hci = 0\nmin_rssi = -70\nrules = [\"a.b=c;mode=passive\"]\nlog_level = \"debug\""
+                .parse()
+                .unwrap();
+        assert_eq!(
+            before.changed(&after).into_iter().collect::<Vec<_>>(),
+            ["log_level", "min_rssi", "plaintext"]
+        );
+        assert!(before.changed(&before).is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+use bytes::{Bytes, BytesMut};
+use log::info;
+use protobuf::MessageFull;
+use snow::StatelessTransportState;
+use socket2::{SockRef, TcpKeepalive};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use crate::api::api::BluetoothLEAdvertisementResponse;
+use crate::context::ProxyContext;
+use crate::dispatch::message_info;
+use crate::noise;
+use crate::outbound::{self, Outbound};
+use crate::proto::{decode_frame, Frame, NOISE_PREAMBLE};
+
+// Home Assistant completes the handshake immediately; anything slower is stuck
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Spacing of TCP keepalive probes once the connection has gone idle
+const TCP_KEEPALIVE_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An API client connection: the read side of the socket, whichever frame
+/// helper (plaintext or Noise) was negotiated for it, and the queue feeding
+/// its writer task.
+pub struct ApiConnection {
+    reader: OwnedReadHalf,
+    peer: SocketAddr,
+    buf: BytesMut,
+    noise: Option<Arc<StatelessTransportState>>,
+    recv_nonce: u64,
+    outbound: Arc<Outbound>,
+}
+
+impl ApiConnection {
+    /// Wraps an accepted socket, running the Noise handshake first when an
+    /// encryption key is configured, and starts the writer task.
+    pub async fn accept(ctx: &ProxyContext, mut stream: TcpStream) -> Result<Self, Error> {
+        let peer = stream.peer_addr()?;
+        // Output is batched by the writer instead, and urgent messages go out at once
+        stream.set_nodelay(true)?;
+        // Catches peers that vanished without a FIN (e.g. a rebooted Home
+        // Assistant host) even while we have nothing to send
+        let keepalive = TcpKeepalive::new()
+            .with_time(ctx.keepalive_interval)
+            .with_interval(TCP_KEEPALIVE_PROBE_INTERVAL)
+            .with_retries(ctx.keepalive_misses);
+        SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+        let mut buf = BytesMut::with_capacity(1024);
+
+        let noise = match ctx.encryption_key() {
+            Some(psk) => {
+                let handshake = noise::server_handshake(ctx, &psk, &mut stream, &mut buf);
+                let transport = timeout(HANDSHAKE_TIMEOUT, handshake)
+                    .await
+                    .map_err(|_| Error::new(ErrorKind::TimedOut, "Noise handshake timed out"))??;
+                info!("Encrypted session established with {}", peer.ip());
+                Some(Arc::new(transport))
+            }
+            None => None,
+        };
+
+        let (reader, writer) = stream.into_split();
+        let outbound = Arc::new(Outbound::new(peer, ctx.outbound.clone()));
+        tokio::spawn(outbound::run_writer(
+            outbound.clone(),
+            writer,
+            noise.clone(),
+        ));
+
+        Ok(ApiConnection {
+            reader,
+            peer,
+            buf,
+            noise,
+            recv_nonce: 0,
+            outbound,
+        })
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// The queue to this client, for waiting on its writer outside of a
+    /// borrow of the connection.
+    pub fn outbound(&self) -> Arc<Outbound> {
+        self.outbound.clone()
+    }
+
+    /// Reads whatever the socket has into the receive buffer, returning 0 on
+    /// EOF. Cancel safe, so it can sit in a `select!`.
+    pub async fn read(&mut self) -> Result<usize, Error> {
+        let n = self.reader.read_buf(&mut self.buf).await?;
+        if self.noise.is_none() && self.buf.first() == Some(&NOISE_PREAMBLE) {
+            // Tell an encrypting client we only speak plaintext, the way
+            // ESPHome firmware does, so it can report a useful error.
+            self.outbound.push_raw(b"\x00Bad indicator byte".to_vec())?;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Client requested encryption but plaintext is configured",
+            ));
+        }
+        Ok(n)
+    }
+
+    /// Takes the next complete message from the receive buffer, if any. Any
+    /// error means the stream can't be trusted any more and must be closed.
+    pub fn next_message(&mut self) -> Result<Option<(u32, Bytes)>, Error> {
+        let Some(frame) = decode_frame(&mut self.buf)? else {
+            return Ok(None);
+        };
+        match (frame, &self.noise) {
+            (Frame::Plaintext { msg_type, payload }, None) => Ok(Some((msg_type, payload))),
+            (Frame::Noise(frame), Some(transport)) => {
+                let message = noise::decrypt_message(transport, self.recv_nonce, &frame)?;
+                self.recv_nonce += 1;
+                Ok(Some(message))
+            }
+            (Frame::Noise(_), None) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Encrypted frame on a plaintext connection",
+            )),
+            (Frame::Plaintext { .. }, Some(_)) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Plaintext frame on an encrypted connection",
+            )),
+        }
+    }
+
+    /// Queues a message for the writer. api.proto's `no_delay` messages are
+    /// written right away; anything else waits for the next `flush`, so a
+    /// burst of responses leaves in as few packets as possible.
+    pub async fn send<M: MessageFull>(&mut self, message: &M) -> Result<(), Error> {
+        let info = message_info::<M>();
+        self.outbound
+            .push(info.id, message.write_to_bytes()?, info.no_delay)
+    }
+
+    /// Queues an advertisement; see `Outbound::push_advert` for what
+    /// happens when the client can't keep up.
+    pub fn send_advert(&mut self, advert: &BluetoothLEAdvertisementResponse) -> Result<(), Error> {
+        self.outbound.push_advert(advert)
+    }
+
+    pub fn flush(&mut self) {
+        self.outbound.flush();
+    }
+
+    /// Closes the connection once everything queued has been written.
+    pub fn shutdown(&mut self) {
+        self.outbound.close();
+    }
+}
+
+impl Drop for ApiConnection {
+    fn drop(&mut self) {
+        self.outbound.close();
+    }
+}
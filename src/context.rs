@@ -1,8 +1,209 @@
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::api::api::HomeassistantServiceResponse;
+use crate::ble::{BleCommand, BleStats, ScanSettings};
+use crate::host::HostSensor;
+use crate::outbound::{Outbound, OutboundStats};
+use crate::rules::{Rules, ScanOverrides};
+use crate::services::Service;
+use crate::state::StateStore;
+use crate::systemd;
+use crate::tracker::DeviceTracker;
+
+/// A connection past the handshake, as the metrics and status page see it.
+pub struct ClientInfo {
+    /// From the client's HelloRequest; empty until it's sent
+    pub name: String,
+    pub since: Instant,
+    pub outbound: Arc<Outbound>,
+}
+
 pub struct ProxyContext {
     pub hostname: String,
     pub port: u16,
+    /// Port of the status page and metrics, if served
+    pub webserver_port: Option<u16>,
     pub net_mac: [u8; 6],
     pub bt_mac: [u8; 6],
     pub build_time: &'static str,
     pub version: &'static str,
+    /// Noise pre-shared key; `None` means plaintext. Home Assistant can
+    /// replace it at runtime, new connections pick up the change.
+    pub encryption_key: RwLock<Option<[u8; 32]>>,
+    pub state: StateStore,
+    /// API password clients must send in ConnectRequest, if any
+    pub password: Option<String>,
+    pub outbound: Arc<OutboundStats>,
+    /// Where advertisements come from, for the configuration dump
+    pub advertisement_source: String,
+    /// Idle time before a client is pinged
+    pub keepalive_interval: Duration,
+    /// Unanswered pings before a client is dropped
+    pub keepalive_misses: u32,
+    /// Clock difference to Home Assistant worth a warning; `None` skips the check
+    pub max_clock_skew: Option<Duration>,
+    pub started: Instant,
+    /// Connections past the handshake
+    pub api_clients: AtomicUsize,
+    /// The same connections, by peer address
+    pub clients: Mutex<HashMap<SocketAddr, ClientInfo>>,
+    pub tracker: Arc<DeviceTracker>,
+    /// Requests for the BlueZ listener; `None` when advertisements are
+    /// replayed or synthetic
+    pub ble_commands: Option<mpsc::Sender<BleCommand>>,
+    pub ble_stats: Arc<BleStats>,
+    /// Watched by the BlueZ listener, and by clients to report changes
+    pub scan_settings: watch::Sender<ScanSettings>,
+    /// Host health sensors to offer
+    pub host_sensors: Vec<HostSensor>,
+    /// Addresses whose coming and going fires Home Assistant events
+    pub watched: HashSet<u64>,
+    /// Silence after which a watched device counts as gone
+    pub watch_timeout: Duration,
+    /// Those events, for clients subscribed to Home Assistant services
+    pub presence_events: broadcast::Sender<HomeassistantServiceResponse>,
+    /// Scanner settings driven by Home Assistant entity states
+    pub rules: Rules,
+    /// Advertisements weaker than this (dBm) aren't forwarded, unless a
+    /// rule says otherwise. Changes when the configuration is reloaded.
+    pub min_rssi: RwLock<Option<i32>>,
+    /// API services running local commands
+    pub services: Vec<Service>,
+    /// Set once the proxy is shutting down; clients are asked to disconnect
+    pub shutdown: watch::Sender<bool>,
+    /// Start again once shut down, rather than exit
+    pub restart: AtomicBool,
+    /// The API socket, when systemd passed it in; handed on when restarting
+    /// in place
+    pub listen_fd: Option<RawFd>,
+}
+
+impl ProxyContext {
+    /// Node name reported in DeviceInfoResponse and the Noise server hello.
+    /// Home Assistant checks that the two agree.
+    pub fn device_name(&self) -> String {
+        format!("Linux BT Proxy: {}", self.hostname)
+    }
+
+    pub fn encryption_key(&self) -> Option<[u8; 32]> {
+        *self.encryption_key.read().unwrap()
+    }
+
+    pub fn scan_settings(&self) -> ScanSettings {
+        *self.scan_settings.borrow()
+    }
+
+    /// Changes the scan settings, applied by the listener right away and
+    /// saved for the next start.
+    pub fn update_scan_settings(&self, update: impl FnOnce(&mut ScanSettings)) {
+        let changed = self.scan_settings.send_if_modified(|settings| {
+            let before = *settings;
+            update(settings);
+            *settings != before
+        });
+        if !changed {
+            return;
+        }
+        let settings = self.scan_settings();
+        info!(
+            "Scanning {}, {} mode{}",
+            if settings.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            settings.mode.label().to_lowercase(),
+            if settings.overrides == ScanOverrides::default() {
+                ""
+            } else {
+                " (a rule overrides this for now)"
+            }
+        );
+        if let Err(e) = self.state.save_scan_settings(&settings) {
+            warn!("Failed to save scan settings: {e:#}");
+        }
+    }
+
+    /// Replaces what rules override; not saved, rules are evaluated afresh
+    /// from Home Assistant's states after a restart.
+    pub fn set_scan_overrides(&self, overrides: ScanOverrides) {
+        let changed = self.scan_settings.send_if_modified(|settings| {
+            let changed = settings.overrides != overrides;
+            settings.overrides = overrides;
+            changed
+        });
+        if changed {
+            info!("Rules now override scanning with {overrides:?}");
+        }
+    }
+
+    /// Starts a graceful shutdown: clients are asked to disconnect, then
+    /// `main` stops scanning and withdraws the mDNS announcement. With
+    /// `restart`, it then starts the proxy over in place.
+    pub fn shut_down(&self, restart: bool) {
+        if restart {
+            self.restart.store(true, Ordering::Relaxed);
+        } else {
+            systemd::notify_stopping();
+        }
+        self.shutdown.send_replace(true);
+    }
+
+    /// RSSI threshold in effect for forwarding.
+    pub fn min_rssi(&self) -> Option<i32> {
+        self.scan_settings
+            .borrow()
+            .overrides
+            .min_rssi
+            .or(*self.min_rssi.read().unwrap())
+    }
+}
+
+#[cfg(test)]
+impl ProxyContext {
+    /// A plaintext proxy on a virtual adapter with every option at its
+    /// default, for tests of what reports on the context.
+    pub fn for_tests() -> Self {
+        ProxyContext {
+            hostname: "test".to_string(),
+            port: 6053,
+            webserver_port: Some(8080),
+            net_mac: [2, 0, 0, 0, 0, 1],
+            bt_mac: [2, 0, 0, 0, 0, 2],
+            build_time: "",
+            version: env!("CARGO_PKG_VERSION"),
+            encryption_key: RwLock::new(None),
+            state: StateStore::new("/nonexistent".into()),
+            password: None,
+            outbound: Arc::default(),
+            advertisement_source: "test".to_string(),
+            keepalive_interval: Duration::from_secs(60),
+            keepalive_misses: 2,
+            max_clock_skew: None,
+            started: Instant::now(),
+            api_clients: AtomicUsize::new(0),
+            clients: Mutex::default(),
+            tracker: Arc::default(),
+            ble_commands: None,
+            ble_stats: Arc::default(),
+            scan_settings: watch::Sender::new(ScanSettings::default()),
+            host_sensors: Vec::new(),
+            watched: HashSet::new(),
+            watch_timeout: Duration::from_secs(60),
+            presence_events: broadcast::channel(1).0,
+            rules: Rules::new(Vec::new()),
+            min_rssi: RwLock::new(None),
+            services: Vec::new(),
+            shutdown: watch::Sender::new(false),
+            restart: AtomicBool::new(false),
+            listen_fd: None,
+        }
+    }
 }
@@ -0,0 +1,161 @@
+use protobuf::{Message, MessageFull};
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::LazyLock;
+
+use crate::api::api::{
+    BluetoothScannerSetModeRequest, ButtonCommandRequest, ConnectRequest, DeviceInfoRequest,
+    DisconnectRequest, DisconnectResponse, ExecuteServiceRequest, GetTimeRequest, GetTimeResponse,
+    HelloRequest, HomeAssistantStateResponse, ListEntitiesRequest, NoiseEncryptionSetKeyRequest,
+    PingRequest, PingResponse, SelectCommandRequest, SubscribeBluetoothConnectionsFreeRequest,
+    SubscribeBluetoothLEAdvertisementsRequest, SubscribeHomeAssistantStatesRequest,
+    SubscribeHomeassistantServicesRequest, SubscribeLogsRequest, SubscribeStatesRequest,
+    SwitchCommandRequest, UnsubscribeBluetoothLEAdvertisementsRequest,
+};
+use crate::api::api_options::APISourceType;
+use crate::api::{api, api_options};
+
+/// What api.proto says about one message, read from its custom options.
+#[derive(Debug)]
+pub struct MessageInfo {
+    pub id: u32,
+    pub name: String,
+    pub source: APISourceType,
+    /// Send right away instead of waiting for more output to batch with it
+    pub no_delay: bool,
+    /// Only for requests (the input of an rpc): may be sent before HelloRequest
+    pub needs_setup_connection: bool,
+    /// Only for requests: may be sent before a successful ConnectRequest
+    pub needs_authentication: bool,
+}
+
+impl MessageInfo {
+    /// Whether a client may send this message at all
+    pub fn client_may_send(&self) -> bool {
+        self.source != APISourceType::SOURCE_SERVER
+    }
+}
+
+static REGISTRY: LazyLock<HashMap<u32, MessageInfo>> = LazyLock::new(build_registry);
+
+fn build_registry() -> HashMap<u32, MessageInfo> {
+    let file = api::file_descriptor();
+
+    // Session requirements are options on the rpc taking the message as input
+    let mut methods = HashMap::new();
+    for service in file.services() {
+        for method in service.methods() {
+            let options = method.proto().options.get_or_default();
+            methods.insert(
+                method.input_type().full_name().to_string(),
+                (
+                    api_options::exts::needs_setup_connection
+                        .get(options)
+                        .unwrap_or(true),
+                    api_options::exts::needs_authentication
+                        .get(options)
+                        .unwrap_or(true),
+                ),
+            );
+        }
+    }
+
+    let mut registry = HashMap::new();
+    for message in file.messages() {
+        let options = message.proto().options.get_or_default();
+        let Some(id) = api_options::exts::id.get(options).filter(|&id| id != 0) else {
+            continue; // Not sent on its own, only nested in other messages
+        };
+        let (needs_setup_connection, needs_authentication) = methods
+            .get(message.full_name())
+            .copied()
+            .unwrap_or((true, true));
+        registry.insert(
+            id,
+            MessageInfo {
+                id,
+                name: message.name().to_string(),
+                source: api_options::exts::source
+                    .get(options)
+                    .and_then(|source| source.enum_value().ok())
+                    .unwrap_or(APISourceType::SOURCE_BOTH),
+                no_delay: api_options::exts::no_delay.get(options).unwrap_or(false),
+                needs_setup_connection,
+                needs_authentication,
+            },
+        );
+    }
+    registry
+}
+
+/// Looks up a message by its wire id.
+pub fn lookup(id: u32) -> Option<&'static MessageInfo> {
+    REGISTRY.get(&id)
+}
+
+pub fn message_info<M: MessageFull>() -> &'static MessageInfo {
+    let id = crate::proto::get_message_id::<M>() as u32;
+    lookup(id).expect("Message id missing from registry")
+}
+
+type Decoder = fn(&[u8]) -> protobuf::Result<ClientMessage>;
+
+/// Declares the client messages the proxy handles: one `ClientMessage`
+/// variant each, plus a decoder table keyed by the id api.proto gives them.
+macro_rules! client_messages {
+    ($($variant:ident($message:ty)),* $(,)?) => {
+        // Some handlers only care that the message arrived
+        #[allow(dead_code)]
+        pub enum ClientMessage {
+            $($variant($message),)*
+        }
+
+        static DECODERS: LazyLock<HashMap<u32, Decoder>> = LazyLock::new(|| {
+            let mut decoders: HashMap<u32, Decoder> = HashMap::new();
+            $(
+                decoders.insert(message_info::<$message>().id, |payload| {
+                    Ok(ClientMessage::$variant(<$message>::parse_from_bytes(payload)?))
+                });
+            )*
+            decoders
+        });
+    };
+}
+
+client_messages! {
+    Hello(HelloRequest),
+    Connect(ConnectRequest),
+    Disconnect(DisconnectRequest),
+    DisconnectAck(DisconnectResponse),
+    Ping(PingRequest),
+    Pong(PingResponse),
+    DeviceInfo(DeviceInfoRequest),
+    GetTime(GetTimeRequest),
+    Time(GetTimeResponse),
+    ListEntities(ListEntitiesRequest),
+    SubscribeLogs(SubscribeLogsRequest),
+    SubscribeStates(SubscribeStatesRequest),
+    SubscribeHomeassistantServices(SubscribeHomeassistantServicesRequest),
+    SubscribeHomeAssistantStates(SubscribeHomeAssistantStatesRequest),
+    HomeAssistantState(HomeAssistantStateResponse),
+    SubscribeAdvertisements(SubscribeBluetoothLEAdvertisementsRequest),
+    UnsubscribeAdvertisements(UnsubscribeBluetoothLEAdvertisementsRequest),
+    SubscribeConnectionsFree(SubscribeBluetoothConnectionsFreeRequest),
+    NoiseEncryptionSetKey(NoiseEncryptionSetKeyRequest),
+    ButtonCommand(ButtonCommandRequest),
+    ExecuteService(ExecuteServiceRequest),
+    SwitchCommand(SwitchCommandRequest),
+    SelectCommand(SelectCommandRequest),
+    ScannerSetMode(BluetoothScannerSetModeRequest),
+}
+
+impl ClientMessage {
+    /// Parses a message the proxy has a handler for; `Ok(None)` for any
+    /// other id, known to api.proto or not.
+    pub fn decode(msg_type: u32, payload: &[u8]) -> Result<Option<Self>, Error> {
+        match DECODERS.get(&msg_type) {
+            Some(decode) => Ok(Some(decode(payload)?)),
+            None => Ok(None),
+        }
+    }
+}
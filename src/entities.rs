@@ -0,0 +1,549 @@
+use protobuf::EnumOrUnknown;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+
+use crate::api::api::{
+    BinarySensorStateResponse, EntityCategory, ListEntitiesBinarySensorResponse,
+    ListEntitiesButtonResponse, ListEntitiesSelectResponse, ListEntitiesSensorResponse,
+    ListEntitiesSwitchResponse, SelectStateResponse, SensorStateClass, SensorStateResponse,
+    SwitchStateResponse,
+};
+use crate::ble::ScanMode;
+use crate::context::ProxyContext;
+use crate::host::{self, HostSensor};
+use crate::utils::format_mac;
+
+/// Home Assistant keys its entity registry on `unique_id`, so it must stay
+/// the same across restarts and differ between proxies: the adapter's MAC
+/// plus the entity's domain and object id.
+pub fn unique_id(ctx: &ProxyContext, domain: &str, object_id: &str) -> String {
+    format!(
+        "{}-{domain}-{object_id}",
+        format_mac(&ctx.bt_mac, "").to_lowercase()
+    )
+}
+
+/// The `key` clients use to refer to an entity in state and command
+/// messages: a 32-bit FNV-1a hash of its unique id.
+pub fn entity_key(unique_id: &str) -> u32 {
+    unique_id.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// A read-only numeric entity.
+pub struct Sensor {
+    pub object_id: &'static str,
+    pub name: &'static str,
+    pub icon: &'static str,
+    pub unit: &'static str,
+    pub device_class: &'static str,
+    pub state_class: SensorStateClass,
+    pub accuracy_decimals: i32,
+    /// Current value; `None` while there's nothing to report yet
+    pub read: fn(&ProxyContext) -> Option<f32>,
+}
+
+/// Sensors describing the proxy itself, shown under Diagnostic in Home Assistant.
+pub static DIAGNOSTIC_SENSORS: &[Sensor] = &[
+    Sensor {
+        object_id: "uptime",
+        name: "Uptime",
+        icon: "mdi:timer-outline",
+        unit: "s",
+        device_class: "duration",
+        state_class: SensorStateClass::STATE_CLASS_TOTAL_INCREASING,
+        accuracy_decimals: 0,
+        read: |ctx| Some(ctx.started.elapsed().as_secs() as f32),
+    },
+    Sensor {
+        object_id: "advertisements_per_second",
+        name: "Advertisements per second",
+        icon: "mdi:bluetooth-audio",
+        unit: "adv/s",
+        device_class: "",
+        state_class: SensorStateClass::STATE_CLASS_MEASUREMENT,
+        accuracy_decimals: 1,
+        read: |ctx| Some(ctx.tracker.adverts_per_second()),
+    },
+    Sensor {
+        object_id: "known_devices",
+        name: "Known devices",
+        icon: "mdi:devices",
+        unit: "",
+        device_class: "",
+        state_class: SensorStateClass::STATE_CLASS_MEASUREMENT,
+        accuracy_decimals: 0,
+        read: |ctx| Some(ctx.tracker.known_devices() as f32),
+    },
+    Sensor {
+        object_id: "api_clients",
+        name: "API clients",
+        icon: "mdi:lan-connect",
+        unit: "",
+        device_class: "",
+        state_class: SensorStateClass::STATE_CLASS_MEASUREMENT,
+        accuracy_decimals: 0,
+        read: |ctx| Some(ctx.api_clients.load(Ordering::Relaxed) as f32),
+    },
+    Sensor {
+        object_id: "dropped_advertisements",
+        name: "Dropped advertisements",
+        icon: "mdi:delete-alert-outline",
+        unit: "",
+        device_class: "",
+        state_class: SensorStateClass::STATE_CLASS_TOTAL_INCREASING,
+        accuracy_decimals: 0,
+        read: |ctx| Some(ctx.outbound.adverts_dropped.load(Ordering::Relaxed) as f32),
+    },
+    Sensor {
+        object_id: "free_connection_slots",
+        name: "Free connection slots",
+        icon: "mdi:bluetooth-connect",
+        unit: "",
+        device_class: "",
+        state_class: SensorStateClass::STATE_CLASS_MEASUREMENT,
+        accuracy_decimals: 0,
+        // No active (GATT) connections are proxied yet
+        read: |_| Some(0.0),
+    },
+    Sensor {
+        object_id: "rssi_noise_floor",
+        name: "RSSI noise floor",
+        icon: "mdi:signal-distance-variant",
+        unit: "dBm",
+        device_class: "signal_strength",
+        state_class: SensorStateClass::STATE_CLASS_MEASUREMENT,
+        accuracy_decimals: 0,
+        read: |ctx| ctx.tracker.rssi_noise_floor(),
+    },
+];
+
+/// Host health sensors, each listed only when enabled.
+pub static HOST_SENSORS: &[(HostSensor, Sensor)] = &[
+    (
+        HostSensor::CpuTemperature,
+        Sensor {
+            object_id: "cpu_temperature",
+            name: "CPU temperature",
+            icon: "mdi:thermometer",
+            unit: "°C",
+            device_class: "temperature",
+            state_class: SensorStateClass::STATE_CLASS_MEASUREMENT,
+            accuracy_decimals: 1,
+            read: |_| host::cpu_temperature(),
+        },
+    ),
+    (
+        HostSensor::LoadAverage,
+        Sensor {
+            object_id: "load_average",
+            name: "Load average",
+            icon: "mdi:cpu-64-bit",
+            unit: "",
+            device_class: "",
+            state_class: SensorStateClass::STATE_CLASS_MEASUREMENT,
+            accuracy_decimals: 2,
+            read: |_| host::load_average(),
+        },
+    ),
+    (
+        HostSensor::MemoryUsage,
+        Sensor {
+            object_id: "memory_usage",
+            name: "Memory usage",
+            icon: "mdi:memory",
+            unit: "%",
+            device_class: "",
+            state_class: SensorStateClass::STATE_CLASS_MEASUREMENT,
+            accuracy_decimals: 1,
+            read: |_| host::memory_usage(),
+        },
+    ),
+    (
+        HostSensor::DiskFree,
+        Sensor {
+            object_id: "disk_free",
+            name: "Disk free",
+            icon: "mdi:harddisk",
+            unit: "%",
+            device_class: "",
+            state_class: SensorStateClass::STATE_CLASS_MEASUREMENT,
+            accuracy_decimals: 1,
+            read: |_| host::disk_free(),
+        },
+    ),
+];
+
+/// Every sensor to list: the diagnostics, then enabled host sensors.
+pub fn sensors(ctx: &ProxyContext) -> impl Iterator<Item = &'static Sensor> + '_ {
+    let host = HOST_SENSORS
+        .iter()
+        .filter(|(kind, _)| ctx.host_sensors.contains(kind))
+        .map(|(_, sensor)| sensor);
+    DIAGNOSTIC_SENSORS.iter().chain(host)
+}
+
+impl Sensor {
+    fn unique_id(&self, ctx: &ProxyContext) -> String {
+        unique_id(ctx, "sensor", self.object_id)
+    }
+
+    pub fn key(&self, ctx: &ProxyContext) -> u32 {
+        entity_key(&self.unique_id(ctx))
+    }
+
+    pub fn list_response(&self, ctx: &ProxyContext) -> ListEntitiesSensorResponse {
+        let unique_id = self.unique_id(ctx);
+        ListEntitiesSensorResponse {
+            object_id: self.object_id.to_string(),
+            key: entity_key(&unique_id),
+            name: self.name.to_string(),
+            unique_id,
+            icon: self.icon.to_string(),
+            unit_of_measurement: self.unit.to_string(),
+            accuracy_decimals: self.accuracy_decimals,
+            device_class: self.device_class.to_string(),
+            state_class: EnumOrUnknown::new(self.state_class),
+            entity_category: EnumOrUnknown::new(EntityCategory::ENTITY_CATEGORY_DIAGNOSTIC),
+            ..Default::default()
+        }
+    }
+
+    pub fn state_response(&self, ctx: &ProxyContext) -> SensorStateResponse {
+        let state = (self.read)(ctx);
+        SensorStateResponse {
+            key: self.key(ctx),
+            state: state.unwrap_or(0.0),
+            missing_state: state.is_none(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A read-only on/off entity.
+pub struct BinarySensor {
+    pub object_id: &'static str,
+    pub name: &'static str,
+    pub icon: &'static str,
+    pub device_class: &'static str,
+    pub read: fn(&ProxyContext) -> bool,
+}
+
+pub static HOST_BINARY_SENSORS: &[(HostSensor, BinarySensor)] = &[(
+    HostSensor::Bluetoothd,
+    BinarySensor {
+        object_id: "bluetoothd",
+        name: "bluetoothd",
+        icon: "mdi:bluetooth",
+        device_class: "running",
+        read: |_| host::bluetoothd_running(),
+    },
+)];
+
+pub fn binary_sensors(ctx: &ProxyContext) -> impl Iterator<Item = &'static BinarySensor> + '_ {
+    HOST_BINARY_SENSORS
+        .iter()
+        .filter(|(kind, _)| ctx.host_sensors.contains(kind))
+        .map(|(_, sensor)| sensor)
+}
+
+impl BinarySensor {
+    fn unique_id(&self, ctx: &ProxyContext) -> String {
+        unique_id(ctx, "binary_sensor", self.object_id)
+    }
+
+    pub fn key(&self, ctx: &ProxyContext) -> u32 {
+        entity_key(&self.unique_id(ctx))
+    }
+
+    pub fn list_response(&self, ctx: &ProxyContext) -> ListEntitiesBinarySensorResponse {
+        let unique_id = self.unique_id(ctx);
+        ListEntitiesBinarySensorResponse {
+            object_id: self.object_id.to_string(),
+            key: entity_key(&unique_id),
+            name: self.name.to_string(),
+            unique_id,
+            icon: self.icon.to_string(),
+            device_class: self.device_class.to_string(),
+            entity_category: EnumOrUnknown::new(EntityCategory::ENTITY_CATEGORY_DIAGNOSTIC),
+            ..Default::default()
+        }
+    }
+
+    pub fn state_response(&self, ctx: &ProxyContext) -> BinarySensorStateResponse {
+        BinarySensorStateResponse {
+            key: self.key(ctx),
+            state: (self.read)(ctx),
+            ..Default::default()
+        }
+    }
+}
+
+/// What pressing a button does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ButtonAction {
+    RestartDiscovery,
+    PurgeStaleDevices,
+    RestartDaemon,
+}
+
+/// A stateless action entity, pressed with `ButtonCommandRequest`.
+pub struct Button {
+    pub object_id: &'static str,
+    pub name: &'static str,
+    pub icon: &'static str,
+    pub device_class: &'static str,
+    pub action: ButtonAction,
+}
+
+/// Fixes for a stuck scanner that would otherwise need a shell on the host.
+pub static BUTTONS: &[Button] = &[
+    Button {
+        object_id: "restart_discovery",
+        name: "Restart discovery",
+        icon: "mdi:bluetooth-settings",
+        device_class: "",
+        action: ButtonAction::RestartDiscovery,
+    },
+    Button {
+        object_id: "purge_stale_devices",
+        name: "Purge stale BlueZ devices",
+        icon: "mdi:broom",
+        device_class: "",
+        action: ButtonAction::PurgeStaleDevices,
+    },
+    Button {
+        object_id: "restart",
+        name: "Restart",
+        icon: "mdi:restart",
+        device_class: "restart",
+        action: ButtonAction::RestartDaemon,
+    },
+];
+
+impl Button {
+    fn unique_id(&self, ctx: &ProxyContext) -> String {
+        unique_id(ctx, "button", self.object_id)
+    }
+
+    pub fn key(&self, ctx: &ProxyContext) -> u32 {
+        entity_key(&self.unique_id(ctx))
+    }
+
+    /// Discovery buttons need BlueZ; replayed and synthetic sources have none.
+    pub fn available(&self, ctx: &ProxyContext) -> bool {
+        self.action == ButtonAction::RestartDaemon || has_adapter(ctx)
+    }
+
+    pub fn find(ctx: &ProxyContext, key: u32) -> Option<&'static Button> {
+        BUTTONS
+            .iter()
+            .find(|button| button.available(ctx) && button.key(ctx) == key)
+    }
+
+    pub fn list_response(&self, ctx: &ProxyContext) -> ListEntitiesButtonResponse {
+        let unique_id = self.unique_id(ctx);
+        ListEntitiesButtonResponse {
+            object_id: self.object_id.to_string(),
+            key: entity_key(&unique_id),
+            name: self.name.to_string(),
+            unique_id,
+            icon: self.icon.to_string(),
+            device_class: self.device_class.to_string(),
+            entity_category: EnumOrUnknown::new(EntityCategory::ENTITY_CATEGORY_CONFIG),
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether there's a BlueZ adapter to control.
+fn has_adapter(ctx: &ProxyContext) -> bool {
+    ctx.ble_commands.is_some()
+}
+
+/// A writable on/off entity, set with `SwitchCommandRequest`.
+pub struct Switch {
+    pub object_id: &'static str,
+    pub name: &'static str,
+    pub icon: &'static str,
+    pub read: fn(&ProxyContext) -> bool,
+    pub write: fn(&ProxyContext, bool),
+}
+
+/// Scanner controls, so scanning can be paused from an automation.
+pub static SWITCHES: &[Switch] = &[Switch {
+    object_id: "scanning",
+    name: "Scanning",
+    icon: "mdi:bluetooth-settings",
+    read: |ctx| ctx.scan_settings().is_scanning(),
+    write: |ctx, on| ctx.update_scan_settings(|settings| settings.enabled = on),
+}];
+
+impl Switch {
+    fn unique_id(&self, ctx: &ProxyContext) -> String {
+        unique_id(ctx, "switch", self.object_id)
+    }
+
+    pub fn key(&self, ctx: &ProxyContext) -> u32 {
+        entity_key(&self.unique_id(ctx))
+    }
+
+    pub fn available(&self, ctx: &ProxyContext) -> bool {
+        has_adapter(ctx)
+    }
+
+    pub fn find(ctx: &ProxyContext, key: u32) -> Option<&'static Switch> {
+        SWITCHES
+            .iter()
+            .find(|switch| switch.available(ctx) && switch.key(ctx) == key)
+    }
+
+    pub fn list_response(&self, ctx: &ProxyContext) -> ListEntitiesSwitchResponse {
+        let unique_id = self.unique_id(ctx);
+        ListEntitiesSwitchResponse {
+            object_id: self.object_id.to_string(),
+            key: entity_key(&unique_id),
+            name: self.name.to_string(),
+            unique_id,
+            icon: self.icon.to_string(),
+            entity_category: EnumOrUnknown::new(EntityCategory::ENTITY_CATEGORY_CONFIG),
+            ..Default::default()
+        }
+    }
+
+    pub fn state_response(&self, ctx: &ProxyContext) -> SwitchStateResponse {
+        SwitchStateResponse {
+            key: self.key(ctx),
+            state: (self.read)(ctx),
+            ..Default::default()
+        }
+    }
+}
+
+/// A writable choice between fixed options, set with `SelectCommandRequest`.
+pub struct Select {
+    pub object_id: &'static str,
+    pub name: &'static str,
+    pub icon: &'static str,
+    pub options: &'static [&'static str],
+    pub read: fn(&ProxyContext) -> &'static str,
+    /// Sets one of `options`
+    pub write: fn(&ProxyContext, &str),
+}
+
+pub static SELECTS: &[Select] = &[Select {
+    object_id: "scan_mode",
+    name: "Scan mode",
+    icon: "mdi:radar",
+    options: ScanMode::LABELS,
+    read: |ctx| ctx.scan_settings().effective_mode().label(),
+    write: |ctx, option| {
+        if let Some(mode) = ScanMode::from_label(option) {
+            ctx.update_scan_settings(|settings| settings.mode = mode);
+        }
+    },
+}];
+
+impl Select {
+    fn unique_id(&self, ctx: &ProxyContext) -> String {
+        unique_id(ctx, "select", self.object_id)
+    }
+
+    pub fn key(&self, ctx: &ProxyContext) -> u32 {
+        entity_key(&self.unique_id(ctx))
+    }
+
+    pub fn available(&self, ctx: &ProxyContext) -> bool {
+        has_adapter(ctx)
+    }
+
+    pub fn find(ctx: &ProxyContext, key: u32) -> Option<&'static Select> {
+        SELECTS
+            .iter()
+            .find(|select| select.available(ctx) && select.key(ctx) == key)
+    }
+
+    pub fn list_response(&self, ctx: &ProxyContext) -> ListEntitiesSelectResponse {
+        let unique_id = self.unique_id(ctx);
+        ListEntitiesSelectResponse {
+            object_id: self.object_id.to_string(),
+            key: entity_key(&unique_id),
+            name: self.name.to_string(),
+            unique_id,
+            icon: self.icon.to_string(),
+            options: self.options.iter().map(|o| o.to_string()).collect(),
+            entity_category: EnumOrUnknown::new(EntityCategory::ENTITY_CATEGORY_CONFIG),
+            ..Default::default()
+        }
+    }
+
+    pub fn state_response(&self, ctx: &ProxyContext) -> SelectStateResponse {
+        SelectStateResponse {
+            key: self.key(ctx),
+            state: (self.read)(ctx).to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The state message of any entity that has one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntityState {
+    Sensor(SensorStateResponse),
+    BinarySensor(BinarySensorStateResponse),
+    Switch(SwitchStateResponse),
+    Select(SelectStateResponse),
+}
+
+impl EntityState {
+    fn key(&self) -> u32 {
+        match self {
+            EntityState::Sensor(state) => state.key,
+            EntityState::BinarySensor(state) => state.key,
+            EntityState::Switch(state) => state.key,
+            EntityState::Select(state) => state.key,
+        }
+    }
+}
+
+/// Every entity state there currently is.
+fn current_states(ctx: &ProxyContext) -> Vec<EntityState> {
+    let sensors = sensors(ctx).map(|sensor| EntityState::Sensor(sensor.state_response(ctx)));
+    let binary_sensors =
+        binary_sensors(ctx).map(|sensor| EntityState::BinarySensor(sensor.state_response(ctx)));
+    let switches = SWITCHES
+        .iter()
+        .filter(|switch| switch.available(ctx))
+        .map(|switch| EntityState::Switch(switch.state_response(ctx)));
+    let selects = SELECTS
+        .iter()
+        .filter(|select| select.available(ctx))
+        .map(|select| EntityState::Select(select.state_response(ctx)));
+    sensors
+        .chain(binary_sensors)
+        .chain(switches)
+        .chain(selects)
+        .collect()
+}
+
+/// Per-client record of the states last sent, so updates only carry what
+/// changed.
+#[derive(Default)]
+pub struct StateCache {
+    sent: HashMap<u32, EntityState>,
+}
+
+impl StateCache {
+    /// States that differ from what this client has, all of them the first time.
+    pub fn changed(&mut self, ctx: &ProxyContext) -> Vec<EntityState> {
+        let mut changed = Vec::new();
+        for state in current_states(ctx) {
+            if self.sent.get(&state.key()) != Some(&state) {
+                self.sent.insert(state.key(), state.clone());
+                changed.push(state);
+            }
+        }
+        changed
+    }
+}
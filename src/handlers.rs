@@ -1,35 +1,40 @@
-use protobuf::Message;
-//use protobuf::{EnumOrUnknown, Message};
+use clap::ValueEnum;
+use protobuf::EnumOrUnknown;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::api::api::{
-    BluetoothConnectionsFreeResponse, //,
-    //  SensorStateClass, ListEntitiesSensorResponse
-    BluetoothLEAdvertisementResponse,
-    ConnectRequest,
-    ConnectResponse,
-    DeviceInfoRequest,
-    DeviceInfoResponse,
-    DisconnectRequest,
-    DisconnectResponse,
-    HelloRequest,
-    HelloResponse,
-    ListEntitiesDoneResponse,
-    ListEntitiesRequest,
-    PingRequest,
-    PingResponse,
-    SubscribeBluetoothConnectionsFreeRequest,
-    SubscribeBluetoothLEAdvertisementsRequest,
+    BluetoothConnectionsFreeResponse, BluetoothLEAdvertisementResponse, BluetoothScannerMode,
+    BluetoothScannerSetModeRequest, BluetoothScannerState, BluetoothScannerStateResponse,
+    ButtonCommandRequest, ConnectRequest, ConnectResponse, DeviceInfoRequest, DeviceInfoResponse,
+    DisconnectRequest, DisconnectResponse, ExecuteServiceRequest, GetTimeRequest, GetTimeResponse,
+    HelloRequest, HelloResponse, HomeAssistantStateResponse, HomeassistantServiceResponse,
+    ListEntitiesDoneResponse, ListEntitiesRequest, LogLevel, NoiseEncryptionSetKeyRequest,
+    NoiseEncryptionSetKeyResponse, PingRequest, PingResponse, SelectCommandRequest,
+    SubscribeBluetoothConnectionsFreeRequest, SubscribeBluetoothLEAdvertisementsRequest,
+    SubscribeHomeAssistantStateResponse, SubscribeHomeAssistantStatesRequest,
+    SubscribeHomeassistantServicesRequest, SubscribeLogsRequest, SubscribeLogsResponse,
+    SubscribeStatesRequest, SwitchCommandRequest,
 };
+use crate::ble::{BleCommand, ScanMode};
+use crate::connection::ApiConnection;
 use crate::context::ProxyContext;
-use crate::proto::{encode_varint, get_message_id};
-use crate::utils::format_mac;
-use log::info;
+use crate::entities::{
+    binary_sensors, sensors, Button, ButtonAction, EntityState, Select, StateCache, Switch,
+    BUTTONS, SELECTS, SWITCHES,
+};
+use crate::logs::{self, LogSubscription};
+use crate::noise::decode_psk;
+use crate::services::Service;
+use crate::session::{API_VERSION_MAJOR, API_VERSION_MINOR};
+use crate::utils::{constant_time_eq, format_mac};
+use log::{debug, info, warn};
+use tokio::sync::broadcast;
 
 // Bluetooth proxy subscription flags (from ESPHome)
 const SUBSCRIPTION_RAW_ADVERTISEMENTS: u32 = 1 << 0;
+// Bluetooth proxy feature flag: reports scanner state, takes scan mode changes
+const FEATURE_STATE_AND_MODE: u32 = 1 << 6;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SubscriptionFlags {
@@ -57,118 +62,163 @@ impl SubscriptionFlags {
     }
 }
 
-fn encode_response<M: Message>(msg_type: u32, message: &M) -> Result<Vec<u8>, std::io::Error> {
-    let size = message.compute_size() as usize;
-
-    let mut out = Vec::with_capacity(1 + 5 + 5 + size); // start + varints + payload
-    out.push(0x00); // Plaintext framing
-
-    out.extend_from_slice(&encode_varint(size as u64));
-    out.extend_from_slice(&encode_varint(msg_type as u64));
-
-    message.write_to_writer(&mut out)?; // ✨ direct append
-
-    Ok(out)
-}
-
-pub async fn hello_request(stream: &mut TcpStream, payload: &[u8]) -> Result<(), std::io::Error> {
+pub async fn hello_request(
+    conn: &mut ApiConnection,
+    req: HelloRequest,
+) -> Result<HelloRequest, std::io::Error> {
     // HelloRequest -> inital contact from HA server
-    info!("Handling HelloRequest from {}", stream.peer_addr()?.ip());
-    let _req = HelloRequest::parse_from_bytes(payload)?;
+    info!("Handling HelloRequest from {}", conn.peer_addr().ip());
+    info!(
+        "Client {} is '{}' speaking API {}.{}",
+        conn.peer_addr().ip(),
+        req.client_info,
+        req.api_version_major,
+        req.api_version_minor
+    );
     let resp = HelloResponse {
-        api_version_major: 1,
-        api_version_minor: 10,
+        api_version_major: API_VERSION_MAJOR,
+        api_version_minor: API_VERSION_MINOR,
         server_info: "linux_bt_proxy".into(),
         name: "Linux Bluetooth Proxy".into(),
         ..Default::default() // fill special_fields
     };
 
-    let hello_resp_type = get_message_id::<HelloResponse>();
-    stream
-        .write_all(&encode_response(hello_resp_type as u32, &resp)?)
-        .await?;
-    Ok(())
+    conn.send(&resp).await?;
+    Ok(req)
 }
 
-pub async fn connect_request(stream: &mut TcpStream, payload: &[u8]) -> Result<(), std::io::Error> {
-    // ConnectRequest -> no pasword plaintext, reply with empty Response
-    info!("Handling ConnectRequest from {}", stream.peer_addr()?.ip());
-    let _req = ConnectRequest::parse_from_bytes(payload)?;
-    let resp = ConnectResponse::new();
-    let connect_resp_type = get_message_id::<ConnectResponse>();
-    stream
-        .write_all(&encode_response(connect_resp_type as u32, &resp)?)
-        .await?;
-    Ok(())
+pub async fn connect_request(
+    ctx: Arc<ProxyContext>,
+    conn: &mut ApiConnection,
+    req: ConnectRequest,
+) -> Result<bool, std::io::Error> {
+    // ConnectRequest -> check the password, if one is configured. Returns
+    // whether the client is now authenticated.
+    info!("Handling ConnectRequest from {}", conn.peer_addr().ip());
+    let authenticated = match &ctx.password {
+        Some(password) => constant_time_eq(req.password.as_bytes(), password.as_bytes()),
+        None => true,
+    };
+    if !authenticated {
+        warn!("Invalid password from {}", conn.peer_addr().ip());
+    }
+    let resp = ConnectResponse {
+        invalid_password: !authenticated,
+        ..Default::default()
+    };
+    conn.send(&resp).await?;
+    Ok(authenticated)
 }
 
 pub async fn disconnect_request(
-    stream: &mut TcpStream,
-    payload: &[u8],
+    conn: &mut ApiConnection,
+    _req: DisconnectRequest,
 ) -> Result<(), std::io::Error> {
     // DisconnectRequest
-    info!(
-        "Handling DisconnectRequest from {}",
-        stream.peer_addr()?.ip()
-    );
-    let _req = DisconnectRequest::parse_from_bytes(payload)?;
+    info!("Handling DisconnectRequest from {}", conn.peer_addr().ip());
     let resp = DisconnectResponse::new();
-    let disconnect_resp_type = get_message_id::<DisconnectResponse>();
-    stream
-        .write_all(&encode_response(disconnect_resp_type as u32, &resp)?)
-        .await?;
-    stream.shutdown().await?;
+    conn.send(&resp).await?;
+    conn.shutdown();
     Ok(())
 }
 
-pub async fn ping_request(stream: &mut TcpStream, payload: &[u8]) -> Result<(), std::io::Error> {
+pub async fn send_disconnect_request(conn: &mut ApiConnection) -> Result<(), std::io::Error> {
+    // We're ending the session: ask the client to disconnect, it answers with
+    // DisconnectResponse
+    info!("Sending DisconnectRequest to {}", conn.peer_addr().ip());
+    conn.send(&DisconnectRequest::new()).await
+}
+
+pub async fn send_ping_request(conn: &mut ApiConnection) -> Result<(), std::io::Error> {
+    // Keepalive: the client answers with PingResponse
+    debug!("Sending keepalive PingRequest to {}", conn.peer_addr().ip());
+    conn.send(&PingRequest::new()).await
+}
+
+pub async fn ping_request(
+    conn: &mut ApiConnection,
+    _req: PingRequest,
+) -> Result<(), std::io::Error> {
     // Ping -> reply with pong (PingResponse, actually)
-    info!("Handling PingRequest from {}", stream.peer_addr()?.ip());
-    let _req = PingRequest::parse_from_bytes(payload)?;
+    info!("Handling PingRequest from {}", conn.peer_addr().ip());
     let resp = PingResponse::new();
-    let ping_resp_type = get_message_id::<PingResponse>();
-    stream
-        .write_all(&encode_response(ping_resp_type as u32, &resp)?)
-        .await?;
+    conn.send(&resp).await?;
     Ok(())
 }
 
+fn epoch_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub async fn get_time_request(
+    conn: &mut ApiConnection,
+    _req: GetTimeRequest,
+) -> Result<(), std::io::Error> {
+    // GetTimeRequest -> our system clock
+    debug!("Handling GetTimeRequest from {}", conn.peer_addr().ip());
+    let resp = GetTimeResponse {
+        epoch_seconds: epoch_seconds() as u32,
+        ..Default::default()
+    };
+    conn.send(&resp).await
+}
+
+pub async fn send_get_time_request(conn: &mut ApiConnection) -> Result<(), std::io::Error> {
+    // Ask Home Assistant for its time, it answers with GetTimeResponse
+    debug!("Requesting time from {}", conn.peer_addr().ip());
+    conn.send(&GetTimeRequest::new()).await
+}
+
+pub fn get_time_response(ctx: &ProxyContext, conn: &ApiConnection, resp: GetTimeResponse) {
+    // GetTimeResponse -> compare Home Assistant's clock with ours. Both only
+    // have second resolution, so small differences are noise.
+    let skew = resp.epoch_seconds as i64 - epoch_seconds() as i64;
+    match ctx.max_clock_skew {
+        Some(max) if skew.unsigned_abs() > max.as_secs() => warn!(
+            "System clock is {}s {} Home Assistant at {}; timestamps in logs and captures \
+             won't line up with it. Is NTP running?",
+            skew.unsigned_abs(),
+            if skew > 0 { "behind" } else { "ahead of" },
+            conn.peer_addr().ip()
+        ),
+        _ => debug!(
+            "Clock skew to Home Assistant at {}: {skew}s",
+            conn.peer_addr().ip()
+        ),
+    }
+}
+
 pub async fn subscribe_bluetooth_connections_free_request(
-    stream: &mut TcpStream,
-    payload: &[u8],
+    conn: &mut ApiConnection,
+    _req: SubscribeBluetoothConnectionsFreeRequest,
 ) -> Result<(), std::io::Error> {
     // Bluetooth Connections Free -> BluetoothConnectionsFreeResponse
     info!(
         "Handling BluetoothConnectionsFree from {}",
-        stream.peer_addr()?.ip()
+        conn.peer_addr().ip()
     );
-    let _req = SubscribeBluetoothConnectionsFreeRequest::parse_from_bytes(payload)?;
     let resp = BluetoothConnectionsFreeResponse {
         free: 0,
         limit: 0,
         ..Default::default()
     };
-    let resp_type = get_message_id::<BluetoothConnectionsFreeResponse>();
-    stream
-        .write_all(&encode_response(resp_type as u32, &resp)?)
-        .await?;
+    conn.send(&resp).await?;
     Ok(())
 }
 
 pub async fn device_info_request(
     ctx: Arc<ProxyContext>,
-    stream: &mut TcpStream,
-    payload: &[u8],
+    conn: &mut ApiConnection,
+    _req: DeviceInfoRequest,
 ) -> Result<(), std::io::Error> {
     // DeviceInfoRequest -> reply with values from ProxyContext
-    info!(
-        "Handling DeviceInfoRequest from {}",
-        stream.peer_addr()?.ip()
-    );
-    let _req = DeviceInfoRequest::parse_from_bytes(payload)?;
+    info!("Handling DeviceInfoRequest from {}", conn.peer_addr().ip());
 
     let resp = DeviceInfoResponse {
-        name: format!("Linux BT Proxy: {}", ctx.hostname),
+        name: ctx.device_name(),
         mac_address: format_mac(&ctx.net_mac, ":"),
 
         // A string describing the ESPHome version. For example "1.10.0"
@@ -191,72 +241,459 @@ pub async fn device_info_request(
         // project_name: "linux_bt_proxy".to_string(),
         // project_version: ctx.version.to_string(),
         legacy_bluetooth_proxy_version: 5,
-        bluetooth_proxy_feature_flags: 0x08 | 0x10 | 0x20, // 0x38
+        bluetooth_proxy_feature_flags: 0x08 | 0x10 | 0x20 // 0x38
+            | if ctx.ble_commands.is_some() { FEATURE_STATE_AND_MODE } else { 0 },
 
         friendly_name: format!("Linux BT Proxy: {}", ctx.hostname),
 
+        uses_password: ctx.password.is_some(),
+
         // The Bluetooth mac address of the device. For example "AC:BC:32:89:0E:AA"
         bluetooth_mac_address: format_mac(&ctx.bt_mac, ":"),
 
-        // Supports receiving and saving api encryption key
-        api_encryption_supported: false,
+        // Supports receiving and saving api encryption key; only offered when
+        // encryption is configured, as ESPHome does
+        api_encryption_supported: ctx.encryption_key().is_some(),
+
+        // Home Assistant links to the status page from the device page
+        webserver_port: ctx.webserver_port.unwrap_or(0) as u32,
         ..Default::default()
     };
-    let device_info_resp_type = get_message_id::<DeviceInfoResponse>();
-    stream
-        .write_all(&encode_response(device_info_resp_type as u32, &resp)?)
-        .await?;
+    conn.send(&resp).await?;
     Ok(())
 }
 
+pub async fn noise_encryption_set_key_request(
+    ctx: Arc<ProxyContext>,
+    conn: &mut ApiConnection,
+    req: NoiseEncryptionSetKeyRequest,
+) -> Result<(), std::io::Error> {
+    // NoiseEncryptionSetKeyRequest -> persist the key, use it for new connections
+    info!(
+        "Handling NoiseEncryptionSetKeyRequest from {}",
+        conn.peer_addr().ip()
+    );
+
+    // Like ESPHome, the key arrives base64 encoded. Without encryption
+    // configured (--plaintext) there's no key to replace: any client could
+    // otherwise switch encryption on and lock Home Assistant out.
+    let key = if ctx.encryption_key().is_none() {
+        Err(anyhow::anyhow!(
+            "encryption is disabled (--plaintext), not accepting a key"
+        ))
+    } else {
+        std::str::from_utf8(&req.key)
+            .map_err(anyhow::Error::from)
+            .and_then(decode_psk)
+            .and_then(|key| {
+                if key.iter().all(|&b| b == 0) {
+                    anyhow::bail!("Refusing all-zero encryption key");
+                }
+                Ok(key)
+            })
+    };
+
+    let success = match key {
+        Ok(key) => match ctx.state.save_noise_key(&key) {
+            Ok(()) => {
+                *ctx.encryption_key.write().unwrap() = Some(key);
+                info!("New API encryption key saved, used from the next connection on");
+                true
+            }
+            Err(e) => {
+                warn!("Failed to save API encryption key: {e:#}");
+                false
+            }
+        },
+        Err(e) => {
+            warn!("Rejected API encryption key: {e:#}");
+            false
+        }
+    };
+
+    let resp = NoiseEncryptionSetKeyResponse {
+        success,
+        ..Default::default()
+    };
+    conn.send(&resp).await?;
+    Ok(())
+}
+
+pub async fn subscribe_logs_request(
+    ctx: Arc<ProxyContext>,
+    conn: &mut ApiConnection,
+    req: SubscribeLogsRequest,
+) -> Result<Option<LogSubscription>, std::io::Error> {
+    // SubscribeLogsRequest -> stream log records at the requested level, after
+    // the configuration if asked for (what `esphome logs` shows on connect)
+    let level = req.level.enum_value_or_default();
+    info!(
+        "Handling SubscribeLogsRequest from {} (level {:?}, dump_config {})",
+        conn.peer_addr().ip(),
+        level,
+        req.dump_config
+    );
+    if req.dump_config {
+        for line in config_lines(&ctx) {
+            let resp = SubscribeLogsResponse {
+                level: EnumOrUnknown::new(LogLevel::LOG_LEVEL_CONFIG),
+                message: format!("[C][{}]: {line}", env!("CARGO_CRATE_NAME")).into_bytes(),
+                ..Default::default()
+            };
+            conn.send(&resp).await?;
+        }
+    }
+    Ok(LogSubscription::new(logs::level_filter(level)))
+}
+
+/// The effective configuration, one log line each. Secrets are only
+/// reported as set or not.
+fn config_lines(ctx: &ProxyContext) -> Vec<String> {
+    let enabled = |on: bool| if on { "enabled" } else { "disabled" };
+    vec![
+        format!("Linux BT Proxy {} (built {})", ctx.version, ctx.build_time),
+        format!("  Name: {}", ctx.device_name()),
+        format!("  MAC: {}", format_mac(&ctx.net_mac, ":")),
+        format!("  Bluetooth MAC: {}", format_mac(&ctx.bt_mac, ":")),
+        format!("  Advertisement source: {}", ctx.advertisement_source),
+        format!("  API port: {}", ctx.port),
+        match ctx.webserver_port {
+            Some(port) => format!("  Web server port: {port}"),
+            None => "  Web server: disabled".to_string(),
+        },
+        format!("  Encryption: {}", enabled(ctx.encryption_key().is_some())),
+        format!("  Password: {}", enabled(ctx.password.is_some())),
+        format!(
+            "  Keepalive: {}s, {} missed pings",
+            ctx.keepalive_interval.as_secs(),
+            ctx.keepalive_misses
+        ),
+        match ctx.max_clock_skew {
+            Some(max) => format!("  Clock skew warning: {}s", max.as_secs()),
+            None => "  Clock skew warning: disabled".to_string(),
+        },
+        format!(
+            "  Scanning: {}, {} mode",
+            enabled(ctx.scan_settings().is_scanning()),
+            ctx.scan_settings().effective_mode().label().to_lowercase()
+        ),
+        format!("  Rules: {}", ctx.rules.len()),
+        format!(
+            "  Services: {}",
+            ctx.services
+                .iter()
+                .map(|service| service.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        match ctx.min_rssi() {
+            Some(min_rssi) => format!("  Minimum RSSI: {min_rssi} dBm"),
+            None => "  Minimum RSSI: none".to_string(),
+        },
+        if ctx.host_sensors.is_empty() {
+            "  Host sensors: none".to_string()
+        } else {
+            format!(
+                "  Host sensors: {}",
+                ctx.host_sensors
+                    .iter()
+                    .filter_map(|sensor| sensor.to_possible_value())
+                    .map(|value| value.get_name().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        },
+        format!(
+            "  Watched devices: {}, lost after {}s",
+            ctx.watched.len(),
+            ctx.watch_timeout.as_secs()
+        ),
+        format!("  State directory: {}", ctx.state.dir().display()),
+    ]
+}
+
 pub async fn list_entities_request(
-    stream: &mut TcpStream,
-    payload: &[u8],
+    ctx: Arc<ProxyContext>,
+    conn: &mut ApiConnection,
+    _req: ListEntitiesRequest,
 ) -> Result<(), std::io::Error> {
-    // ListEntitiesRequest
+    // ListEntitiesRequest -> one List*Response per entity, then done
     info!(
         "Handling ListEntitiesRequest from {}",
-        stream.peer_addr()?.ip()
+        conn.peer_addr().ip()
     );
-    let _req = ListEntitiesRequest::parse_from_bytes(payload)?;
-    //    let resp = ListEntitiesSensorResponse {
-    //        object_id: "uptime".to_string(),
-    //        unique_id: "uptime_sensor".to_string(),
-    //        name: "Proxy Service Uptime".to_string(),
-    //        unit_of_measurement: "s".to_string(),
-    //        accuracy_decimals: 0,
-    //        device_class: "duration".to_string(),
-    //        state_class: EnumOrUnknown::new(SensorStateClass::STATE_CLASS_TOTAL_INCREASING),
-    //        icon: "mdi:clock-time-four-outline".to_string(),
-    //        key: 42 as u32,
-    //        ..Default::default()
-    //    };
-    //    let list_entitities_sensor_resp_type = get_message_id::<ListEntitiesSensorResponse>();
-    //    stream
-    //        .write_all(&encode_response(list_entitities_sensor_resp_type as u32, &resp)?)
-    //        .await?;
+    for sensor in sensors(&ctx) {
+        conn.send(&sensor.list_response(&ctx)).await?;
+    }
+    for sensor in binary_sensors(&ctx) {
+        conn.send(&sensor.list_response(&ctx)).await?;
+    }
+    for button in BUTTONS.iter().filter(|button| button.available(&ctx)) {
+        conn.send(&button.list_response(&ctx)).await?;
+    }
+    for switch in SWITCHES.iter().filter(|switch| switch.available(&ctx)) {
+        conn.send(&switch.list_response(&ctx)).await?;
+    }
+    for select in SELECTS.iter().filter(|select| select.available(&ctx)) {
+        conn.send(&select.list_response(&ctx)).await?;
+    }
+    for service in &ctx.services {
+        conn.send(&service.list_response(&ctx)).await?;
+    }
     let resp = ListEntitiesDoneResponse::new();
-    let list_entitities_done_resp_type = get_message_id::<ListEntitiesDoneResponse>();
-    stream
-        .write_all(&encode_response(
-            list_entitities_done_resp_type as u32,
-            &resp,
-        )?)
-        .await?;
+    conn.send(&resp).await?;
+    Ok(())
+}
+
+pub fn subscribe_homeassistant_services_request(
+    ctx: &ProxyContext,
+    conn: &ApiConnection,
+    _req: SubscribeHomeassistantServicesRequest,
+) -> broadcast::Receiver<HomeassistantServiceResponse> {
+    // SubscribeHomeassistantServicesRequest -> the client takes service
+    // calls and events from us from now on; ours are presence events
+    info!(
+        "Handling SubscribeHomeassistantServicesRequest from {}",
+        conn.peer_addr().ip()
+    );
+    ctx.presence_events.subscribe()
+}
+
+pub async fn subscribe_home_assistant_states_request(
+    ctx: Arc<ProxyContext>,
+    conn: &mut ApiConnection,
+    _req: SubscribeHomeAssistantStatesRequest,
+) -> Result<(), std::io::Error> {
+    // SubscribeHomeAssistantStatesRequest -> the entities our rules watch;
+    // the client sends HomeAssistantStateResponse for each, and on changes
+    info!(
+        "Handling SubscribeHomeAssistantStatesRequest from {}",
+        conn.peer_addr().ip()
+    );
+    for (entity_id, attribute) in ctx.rules.subscriptions() {
+        let resp = SubscribeHomeAssistantStateResponse {
+            entity_id: entity_id.to_string(),
+            attribute: attribute.to_string(),
+            ..Default::default()
+        };
+        conn.send(&resp).await?;
+    }
+    Ok(())
+}
+
+pub fn home_assistant_state_response(
+    ctx: &ProxyContext,
+    conn: &ApiConnection,
+    resp: HomeAssistantStateResponse,
+) {
+    // HomeAssistantStateResponse -> re-evaluate the rules watching it
+    debug!(
+        "{} reports {}{} is '{}'",
+        conn.peer_addr().ip(),
+        resp.entity_id,
+        if resp.attribute.is_empty() {
+            String::new()
+        } else {
+            format!("[{}]", resp.attribute)
+        },
+        resp.state
+    );
+    if let Some(overrides) = ctx
+        .rules
+        .update(&resp.entity_id, &resp.attribute, &resp.state)
+    {
+        ctx.set_scan_overrides(overrides);
+    }
+}
+
+pub async fn subscribe_states_request(
+    ctx: Arc<ProxyContext>,
+    conn: &mut ApiConnection,
+    _req: SubscribeStatesRequest,
+) -> Result<StateCache, std::io::Error> {
+    // SubscribeStatesRequest -> every current state now, changes from then on
+    info!(
+        "Handling SubscribeStatesRequest from {}",
+        conn.peer_addr().ip()
+    );
+    let mut states = StateCache::default();
+    send_state_updates(&ctx, conn, &mut states).await?;
+    Ok(states)
+}
+
+pub async fn send_state_updates(
+    ctx: &ProxyContext,
+    conn: &mut ApiConnection,
+    states: &mut StateCache,
+) -> Result<(), std::io::Error> {
+    for state in states.changed(ctx) {
+        match state {
+            EntityState::Sensor(state) => conn.send(&state).await?,
+            EntityState::BinarySensor(state) => conn.send(&state).await?,
+            EntityState::Switch(state) => conn.send(&state).await?,
+            EntityState::Select(state) => conn.send(&state).await?,
+        }
+    }
+    Ok(())
+}
+
+pub async fn button_command_request(
+    ctx: Arc<ProxyContext>,
+    conn: &mut ApiConnection,
+    req: ButtonCommandRequest,
+) -> Result<(), std::io::Error> {
+    // ButtonCommandRequest -> run the button's action; there's no response,
+    // the outcome is logged
+    let Some(button) = Button::find(&ctx, req.key) else {
+        warn!(
+            "{} pressed unknown button 0x{:08x}",
+            conn.peer_addr().ip(),
+            req.key
+        );
+        return Ok(());
+    };
+    info!("{} pressed '{}'", conn.peer_addr().ip(), button.name);
+    let command = match button.action {
+        ButtonAction::RestartDiscovery => BleCommand::RestartDiscovery,
+        ButtonAction::PurgeStaleDevices => BleCommand::PurgeStaleDevices,
+        ButtonAction::RestartDaemon => {
+            info!("Shutting down to restart");
+            ctx.shut_down(true);
+            return Ok(());
+        }
+    };
+    if let Some(commands) = &ctx.ble_commands {
+        if commands.send(command).await.is_err() {
+            warn!("Bluetooth listener isn't running, can't {command:?}");
+        }
+    }
     Ok(())
 }
 
+pub fn execute_service_request(
+    ctx: &ProxyContext,
+    conn: &ApiConnection,
+    req: ExecuteServiceRequest,
+) {
+    // ExecuteServiceRequest -> start the service's command; there's no
+    // response, its output and exit status are logged
+    let Some(service) = Service::find(ctx, req.key) else {
+        warn!(
+            "{} called unknown service 0x{:08x}",
+            conn.peer_addr().ip(),
+            req.key
+        );
+        return;
+    };
+    info!("{} called service {service}", conn.peer_addr().ip());
+    if let Err(e) = service.execute(&req.args) {
+        warn!("Service {service}: {e}");
+    }
+}
+
+pub fn switch_command_request(ctx: &ProxyContext, conn: &ApiConnection, req: SwitchCommandRequest) {
+    // SwitchCommandRequest -> set the switch; the new state reaches clients
+    // through the settings watch
+    match Switch::find(ctx, req.key) {
+        Some(switch) => {
+            info!(
+                "{} turned '{}' {}",
+                conn.peer_addr().ip(),
+                switch.name,
+                if req.state { "on" } else { "off" }
+            );
+            (switch.write)(ctx, req.state);
+        }
+        None => warn!(
+            "{} switched unknown switch 0x{:08x}",
+            conn.peer_addr().ip(),
+            req.key
+        ),
+    }
+}
+
+pub fn select_command_request(ctx: &ProxyContext, conn: &ApiConnection, req: SelectCommandRequest) {
+    // SelectCommandRequest -> pick the option, if it's one of ours
+    match Select::find(ctx, req.key) {
+        Some(select) if select.options.contains(&req.state.as_str()) => {
+            info!(
+                "{} set '{}' to {}",
+                conn.peer_addr().ip(),
+                select.name,
+                req.state
+            );
+            (select.write)(ctx, &req.state);
+        }
+        Some(select) => warn!(
+            "{} chose invalid option '{}' for '{}'",
+            conn.peer_addr().ip(),
+            req.state,
+            select.name
+        ),
+        None => warn!(
+            "{} chose from unknown select 0x{:08x}",
+            conn.peer_addr().ip(),
+            req.key
+        ),
+    }
+}
+
+pub fn bluetooth_scanner_set_mode_request(
+    ctx: &ProxyContext,
+    conn: &ApiConnection,
+    req: BluetoothScannerSetModeRequest,
+) {
+    // BluetoothScannerSetModeRequest -> Home Assistant's own way of setting
+    // what the scan mode select does
+    let mode = match req.mode.enum_value_or_default() {
+        BluetoothScannerMode::BLUETOOTH_SCANNER_MODE_ACTIVE => ScanMode::Active,
+        BluetoothScannerMode::BLUETOOTH_SCANNER_MODE_PASSIVE => ScanMode::Passive,
+    };
+    info!(
+        "{} requested {} scanning",
+        conn.peer_addr().ip(),
+        mode.label().to_lowercase()
+    );
+    if ctx.ble_commands.is_some() {
+        ctx.update_scan_settings(|settings| settings.mode = mode);
+    }
+}
+
+pub async fn send_scanner_state(
+    ctx: &ProxyContext,
+    conn: &mut ApiConnection,
+) -> Result<(), std::io::Error> {
+    // Only clients told about FEATURE_STATE_AND_MODE expect this
+    if ctx.ble_commands.is_none() {
+        return Ok(());
+    }
+    let settings = ctx.scan_settings();
+    let resp = BluetoothScannerStateResponse {
+        state: EnumOrUnknown::new(if settings.is_scanning() {
+            BluetoothScannerState::BLUETOOTH_SCANNER_STATE_RUNNING
+        } else {
+            BluetoothScannerState::BLUETOOTH_SCANNER_STATE_STOPPED
+        }),
+        mode: EnumOrUnknown::new(match settings.effective_mode() {
+            ScanMode::Active => BluetoothScannerMode::BLUETOOTH_SCANNER_MODE_ACTIVE,
+            ScanMode::Passive => BluetoothScannerMode::BLUETOOTH_SCANNER_MODE_PASSIVE,
+        }),
+        ..Default::default()
+    };
+    conn.send(&resp).await
+}
+
 pub async fn subscribe_bluetooth_le_advertisements_request(
-    stream: &mut TcpStream,
-    payload: &[u8],
+    ctx: &ProxyContext,
+    conn: &mut ApiConnection,
+    req: SubscribeBluetoothLEAdvertisementsRequest,
 ) -> Result<SubscriptionFlags, std::io::Error> {
     // Parse the subscription request to get flags
     info!(
         "Handling SubscribeBluetoothLEAdvertisementsRequest from {}",
-        stream.peer_addr()?.ip()
+        conn.peer_addr().ip()
     );
 
-    let req = SubscribeBluetoothLEAdvertisementsRequest::parse_from_bytes(payload)?;
     let subscription_flags = SubscriptionFlags::from_flags(req.flags);
 
     info!(
@@ -264,16 +701,13 @@ pub async fn subscribe_bluetooth_le_advertisements_request(
         subscription_flags, req.flags
     );
 
+    send_scanner_state(ctx, conn).await?;
     Ok(subscription_flags)
 }
 
-pub async fn forward_ble_advertisement(
-    stream: &mut TcpStream,
-    adv: BluetoothLEAdvertisementResponse,
+pub fn forward_ble_advertisement(
+    conn: &mut ApiConnection,
+    adv: &BluetoothLEAdvertisementResponse,
 ) -> Result<(), std::io::Error> {
-    let ble_adv_res_type = get_message_id::<BluetoothLEAdvertisementResponse>();
-    stream
-        .write_all(&encode_response(ble_adv_res_type as u32, &adv)?)
-        .await?;
-    Ok(())
+    conn.send_advert(adv)
 }
@@ -0,0 +1,100 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fs;
+
+/// Health of the machine the proxy runs on, offered as sensors since there's
+/// often no other Home Assistant agent on it. Each is opt-in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostSensor {
+    CpuTemperature,
+    LoadAverage,
+    MemoryUsage,
+    DiskFree,
+    Bluetoothd,
+}
+
+// Thermal zone types that measure the CPU (Raspberry Pi, Intel, AMD/ARM SoCs)
+const CPU_ZONE_TYPES: &[&str] = &["cpu-thermal", "x86_pkg_temp", "cpu_thermal", "soc_thermal"];
+
+/// Degrees Celsius, from the CPU's thermal zone or else the first one.
+pub fn cpu_temperature() -> Option<f32> {
+    let mut zones: Vec<_> = fs::read_dir("/sys/class/thermal")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("thermal_zone"))
+        })
+        .collect();
+    zones.sort();
+    let cpu_zone = zones.iter().find(|zone| {
+        fs::read_to_string(zone.join("type"))
+            .is_ok_and(|kind| CPU_ZONE_TYPES.contains(&kind.trim()))
+    });
+    let zone = cpu_zone.or(zones.first())?;
+    let millidegrees: f32 = fs::read_to_string(zone.join("temp"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(millidegrees / 1000.0)
+}
+
+/// One minute load average.
+pub fn load_average() -> Option<f32> {
+    fs::read_to_string("/proc/loadavg")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Percentage of memory in use, not counting what the kernel could reclaim.
+pub fn memory_usage() -> Option<f32> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| -> Option<f32> {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    };
+    let total = field("MemTotal")?;
+    let available = field("MemAvailable")?;
+    (total > 0.0).then(|| (total - available) / total * 100.0)
+}
+
+/// Percentage of the root filesystem still available to unprivileged users.
+pub fn disk_free() -> Option<f32> {
+    let root = CString::new("/").ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(root.as_ptr(), &mut stat) } != 0 || stat.f_blocks == 0 {
+        return None;
+    }
+    Some(stat.f_bavail as f32 / stat.f_blocks as f32 * 100.0)
+}
+
+/// Whether a bluetoothd process exists and isn't stopped or a zombie.
+pub fn bluetoothd_running() -> bool {
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return false;
+    };
+    processes.filter_map(|entry| entry.ok()).any(|entry| {
+        let dir = entry.path();
+        let is_bluetoothd =
+            fs::read_to_string(dir.join("comm")).is_ok_and(|comm| comm.trim() == "bluetoothd");
+        // The state follows the parenthesised command name in stat
+        is_bluetoothd
+            && fs::read_to_string(dir.join("stat")).is_ok_and(|stat| {
+                stat.rsplit_once(')')
+                    .and_then(|(_, rest)| rest.split_whitespace().next())
+                    .is_some_and(|state| !matches!(state, "Z" | "X" | "T" | "t"))
+            })
+    })
+}
@@ -0,0 +1,130 @@
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+use crate::context::ProxyContext;
+use crate::{metrics, web};
+
+// Requests are a single GET; anything bigger isn't one of ours
+const MAX_REQUEST: usize = 8 * 1024;
+// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status: "200 OK",
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{status}\n").into_bytes(),
+        }
+    }
+}
+
+pub async fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving HTTP on {addr}");
+    Ok(listener)
+}
+
+/// A bare-bones HTTP/1.1 server for the status page, its JSON API and the
+/// metrics: one GET per connection, answered from the proxy's current state.
+pub async fn run_http_server(ctx: Arc<ProxyContext>, listener: TcpListener) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("HTTP accept failed: {e}");
+                // Likely out of file descriptors; give connections time to close
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(&ctx, stream).await {
+                debug!("HTTP request from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+fn route(ctx: &ProxyContext, path: &str) -> Response {
+    match path {
+        "/" => Response::ok("text/html; charset=utf-8", web::STATUS_PAGE),
+        "/api/status" => Response::ok("application/json", web::status(ctx)),
+        "/metrics" => Response::ok(
+            "text/plain; version=0.0.4; charset=utf-8",
+            metrics::render(ctx),
+        ),
+        _ => Response::error("404 Not Found"),
+    }
+}
+
+async fn handle_request(ctx: &ProxyContext, mut stream: TcpStream) -> std::io::Result<()> {
+    let Some(head) = timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .unwrap_or(Ok(None))?
+    else {
+        return Ok(());
+    };
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (request_line.next(), request_line.next());
+    let response = match (method, target) {
+        (Some("GET" | "HEAD"), Some(target)) => {
+            let path = target.split(['?', '#']).next().unwrap_or_default();
+            route(ctx, path)
+        }
+        (Some(_), Some(_)) => Response::error("405 Method Not Allowed"),
+        _ => Response::error("400 Bad Request"),
+    };
+
+    let mut out = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )
+    .into_bytes();
+    if method != Some("HEAD") {
+        out.extend_from_slice(&response.body);
+    }
+    stream.write_all(&out).await?;
+    stream.shutdown().await
+}
+
+/// The request line and headers; `None` if the client gives up or sends
+/// too much.
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(Some(String::from_utf8_lossy(&buf[..end]).into_owned()));
+        }
+        if buf.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+    }
+}
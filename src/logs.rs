@@ -0,0 +1,205 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use protobuf::EnumOrUnknown;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+use tokio::sync::broadcast;
+
+use crate::api::api::{LogLevel, SubscribeLogsResponse};
+
+// Lines buffered per subscriber before the oldest are skipped
+const LOG_CHANNEL_SIZE: usize = 256;
+
+/// One log record, formatted once for every subscriber.
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub level: Level,
+    pub message: String,
+}
+
+/// Logs through env_logger as before, and also hands every record some API
+/// client subscribed to (with `SubscribeLogsRequest`) to the client tasks.
+struct ProxyLogger {
+    /// Replaced when the configured log level changes
+    env: RwLock<env_logger::Logger>,
+    tx: broadcast::Sender<LogLine>,
+    /// Level each subscription asked for, by subscription id
+    subscribers: Mutex<HashMap<u64, LevelFilter>>,
+    /// Most verbose of those, as a `LevelFilter` index, for the hot path
+    subscribed: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+static LOGGER: OnceLock<ProxyLogger> = OnceLock::new();
+
+/// Installs the logger; replaces `env_logger::init()`, and reads `RUST_LOG`
+/// the same way.
+pub fn init() {
+    let logger = LOGGER.get_or_init(|| ProxyLogger {
+        env: RwLock::new(env_logger::Builder::from_default_env().build()),
+        tx: broadcast::channel(LOG_CHANNEL_SIZE).0,
+        subscribers: Mutex::new(HashMap::new()),
+        subscribed: AtomicUsize::new(LevelFilter::Off as usize),
+        next_id: AtomicU64::new(0),
+    });
+    log::set_logger(logger).expect("Logger already installed");
+    logger.update_max_level();
+}
+
+/// Adds a filter in `RUST_LOG` syntax (e.g. "info" or
+/// "linux_bt_proxy=debug") to what `RUST_LOG` says, or with `None` goes
+/// back to just that.
+pub fn set_filter(filter: Option<&str>) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(filter) = filter {
+        builder.parse_filters(filter);
+    }
+    *logger.env.write().unwrap() = builder.build();
+    logger.update_max_level();
+}
+
+impl ProxyLogger {
+    fn subscribed_level(&self) -> LevelFilter {
+        let index = self.subscribed.load(Ordering::Relaxed);
+        LevelFilter::iter().nth(index).unwrap_or(LevelFilter::Off)
+    }
+
+    /// Records nobody wants are filtered out by the `log` macros before
+    /// they're even formatted, so only go as verbose as someone asked for.
+    fn update_max_level(&self) {
+        let subscribed = self
+            .subscribers
+            .lock()
+            .unwrap()
+            .values()
+            .copied()
+            .max()
+            .unwrap_or(LevelFilter::Off);
+        self.subscribed
+            .store(subscribed as usize, Ordering::Relaxed);
+        let local = self.env.read().unwrap().filter();
+        log::set_max_level(local.max(subscribed));
+    }
+
+    /// Other crates' debug output includes socket level tracing, which
+    /// forwarding over a socket would feed back into itself.
+    fn forwarded(&self, metadata: &Metadata) -> bool {
+        let ours = metadata.target().starts_with(env!("CARGO_CRATE_NAME"));
+        metadata.level() <= self.subscribed_level() && (ours || metadata.level() <= Level::Info)
+    }
+}
+
+impl Log for ProxyLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.env.read().unwrap().enabled(metadata) || self.forwarded(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let env = self.env.read().unwrap();
+        if env.matches(record) {
+            env.log(record);
+        }
+        drop(env);
+        if self.tx.receiver_count() > 0 && self.forwarded(record.metadata()) {
+            let _ = self.tx.send(LogLine {
+                level: record.level(),
+                message: format!(
+                    "[{}][{}]: {}",
+                    level_letter(record.level()),
+                    record.target(),
+                    record.args()
+                ),
+            });
+        }
+    }
+
+    fn flush(&self) {
+        self.env.read().unwrap().flush();
+    }
+}
+
+fn level_letter(level: Level) -> char {
+    match level {
+        Level::Error => 'E',
+        Level::Warn => 'W',
+        Level::Info => 'I',
+        Level::Debug => 'D',
+        Level::Trace => 'V',
+    }
+}
+
+/// Rust has no CONFIG or VERY_VERBOSE; they fold into INFO and TRACE.
+pub fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::LOG_LEVEL_NONE => LevelFilter::Off,
+        LogLevel::LOG_LEVEL_ERROR => LevelFilter::Error,
+        LogLevel::LOG_LEVEL_WARN => LevelFilter::Warn,
+        LogLevel::LOG_LEVEL_INFO | LogLevel::LOG_LEVEL_CONFIG => LevelFilter::Info,
+        LogLevel::LOG_LEVEL_DEBUG => LevelFilter::Debug,
+        LogLevel::LOG_LEVEL_VERBOSE | LogLevel::LOG_LEVEL_VERY_VERBOSE => LevelFilter::Trace,
+    }
+}
+
+pub fn esphome_level(level: Level) -> LogLevel {
+    match level {
+        Level::Error => LogLevel::LOG_LEVEL_ERROR,
+        Level::Warn => LogLevel::LOG_LEVEL_WARN,
+        Level::Info => LogLevel::LOG_LEVEL_INFO,
+        Level::Debug => LogLevel::LOG_LEVEL_DEBUG,
+        Level::Trace => LogLevel::LOG_LEVEL_VERBOSE,
+    }
+}
+
+/// A client's log subscription. Dropping it (with the connection) stops
+/// the extra verbosity it asked for.
+pub struct LogSubscription {
+    id: u64,
+    level: LevelFilter,
+    rx: broadcast::Receiver<LogLine>,
+}
+
+impl LogSubscription {
+    /// `None` if `init` wasn't called.
+    pub fn new(level: LevelFilter) -> Option<Self> {
+        let logger = LOGGER.get()?;
+        let id = logger.next_id.fetch_add(1, Ordering::Relaxed);
+        let rx = logger.tx.subscribe();
+        logger.subscribers.lock().unwrap().insert(id, level);
+        logger.update_max_level();
+        Some(LogSubscription { id, level, rx })
+    }
+
+    /// Next line at or above the subscribed level, as the message to send.
+    /// Lines skipped because the client fell behind are flagged on the next
+    /// one, the way ESPHome sets `send_failed`.
+    pub async fn recv(&mut self) -> Option<SubscribeLogsResponse> {
+        let mut skipped = false;
+        loop {
+            match self.rx.recv().await {
+                Ok(line) if line.level <= self.level => {
+                    return Some(SubscribeLogsResponse {
+                        level: EnumOrUnknown::new(esphome_level(line.level)),
+                        message: line.message.into_bytes(),
+                        send_failed: skipped,
+                        ..Default::default()
+                    });
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => skipped = true,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for LogSubscription {
+    fn drop(&mut self) {
+        if let Some(logger) = LOGGER.get() {
+            logger.subscribers.lock().unwrap().remove(&self.id);
+            logger.update_max_level();
+        }
+    }
+}
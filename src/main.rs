@@ -1,22 +1,60 @@
 mod api;
 mod ble;
+mod config;
+mod connection;
 mod context;
+mod dispatch;
+mod entities;
 mod handlers;
+mod host;
+mod http;
+mod logs;
 mod mdns;
+mod metrics;
+mod noise;
+mod outbound;
+mod presence;
 mod proto;
+mod replay;
+mod rules;
 mod server;
+mod services;
+mod session;
+mod state;
+mod synthetic;
+mod systemd;
+mod tracker;
 mod utils;
+mod web;
 
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use gethostname::gethostname;
 use log::{info, warn};
 use mac_address::get_mac_address;
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{timeout, Duration};
 
+use crate::ble::BleCommand;
+use crate::config::Config;
 use crate::context::ProxyContext;
-use crate::utils::parse_mac;
+use crate::host::HostSensor;
+use crate::replay::parse_replay_speed;
+use crate::rules::{Rule, Rules};
+use crate::services::Service;
+use crate::state::StateStore;
+use crate::synthetic::{parse_population, parse_synthetic_rate, Population, SyntheticStats};
+use crate::utils::{mac_to_address, parse_mac, reexec};
+
+// How long the BlueZ listener gets to stop scanning on shutdown
+const SCANNER_STOP_TIMEOUT: Duration = Duration::from_secs(2);
 
 fn default_hostname() -> String {
     gethostname().to_string_lossy().into_owned()
@@ -26,6 +64,11 @@ fn default_hostname() -> String {
 #[command(name = "linux_bt_proxy")]
 #[command(about = "Bluetooth Proxy Daemon for ESPHome", long_about = None)]
 struct Cli {
+    /// TOML file with settings for any of the options below (by their long
+    /// names, with _ for -); options given here win. Reloaded on SIGHUP.
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
     /// Bluetooth adapter index (e.g. 0 for hci0)
     #[arg(short = 'a', long, default_value_t = 0)]
     hci: u16,
@@ -34,6 +77,11 @@ struct Cli {
     #[arg(short, long, default_value = "[::]:6053")]
     listen: SocketAddr,
 
+    /// Serve a status page, its JSON API and Prometheus metrics over HTTP on
+    /// this address, e.g. "[::]:8080"
+    #[arg(long, value_name = "ADDR")]
+    http_listen: Option<SocketAddr>,
+
     /// Hostname to advertise (default: system hostname)
     #[arg(long, default_value_t = default_hostname())]
     hostname: String,
@@ -41,15 +89,212 @@ struct Cli {
     /// MAC address for mDNS
     #[arg(short, long, value_parser = parse_mac)]
     mac: Option<[u8; 6]>,
+
+    /// File holding the base64 API encryption key (same format as ESPHome's api encryption key)
+    #[arg(long, value_name = "FILE", env = "LINUX_BT_PROXY_ENCRYPTION_KEY_FILE")]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Serve the API without encryption
+    #[arg(long, conflicts_with = "encryption_key_file")]
+    plaintext: bool,
+
+    /// File holding the API password clients must send (the password itself may
+    /// instead be given in LINUX_BT_PROXY_PASSWORD)
+    #[arg(long, value_name = "FILE", env = "LINUX_BT_PROXY_PASSWORD_FILE")]
+    password_file: Option<PathBuf>,
+
+    /// Directory for state kept across restarts, such as keys set by Home Assistant
+    #[arg(
+        long,
+        value_name = "DIR",
+        env = "STATE_DIRECTORY",
+        default_value = "/var/lib/linux-bt-proxy"
+    )]
+    state_dir: PathBuf,
+
+    /// Seconds a client may stay silent before it's sent a PingRequest; also
+    /// the TCP keepalive idle time
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    keepalive_interval: u64,
+
+    /// Unanswered keepalive pings before a client is disconnected
+    #[arg(long, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    keepalive_misses: u32,
+
+    /// Warn when the system clock differs from Home Assistant's by more than
+    /// this many seconds (0 to not ask Home Assistant for its time)
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    max_clock_skew: u64,
+
+    /// Host health sensors to offer Home Assistant, comma separated
+    #[arg(long, value_name = "SENSORS", value_enum, value_delimiter = ',')]
+    host_sensors: Vec<HostSensor>,
+
+    /// Devices to fire Home Assistant events for when they appear and
+    /// disappear, comma separated MAC addresses
+    #[arg(long, value_name = "MACS", value_parser = parse_mac, value_delimiter = ',')]
+    watch: Vec<[u8; 6]>,
+
+    /// Seconds a watched device must go unheard to count as lost (at most
+    /// 300, as long as the proxy remembers devices)
+    #[arg(long, value_name = "SECS", default_value_t = 120, value_parser = clap::value_parser!(u64).range(1..=300))]
+    watch_timeout: u64,
+
+    /// Don't forward advertisements weaker than this (dBm)
+    #[arg(long, value_name = "DBM", allow_hyphen_values = true)]
+    min_rssi: Option<i32>,
+
+    /// Change scanner settings while a Home Assistant entity has some state,
+    /// e.g. "media_player.living_room=playing;mode=passive" (repeatable)
+    #[arg(long = "rule", value_name = "RULE")]
+    rules: Vec<Rule>,
+
+    /// Offer Home Assistant a service running a local command, e.g.
+    /// "reset_adapter=hciconfig hci0 reset"; arguments are passed in
+    /// ARG_<NAME> environment variables (repeatable)
+    #[arg(long = "service", value_name = "SERVICE")]
+    services: Vec<Service>,
+
+    /// Replay advertisements from a capture file (JSONL or btsnoop) instead of using an adapter
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// Replay timing multiplier, 0.001 to 1000 (2.0 = twice as fast, 0 = as fast as possible)
+    #[arg(long, default_value_t = 1.0, value_parser = parse_replay_speed, requires = "replay")]
+    replay_speed: f64,
+
+    /// Start the capture over when it reaches the end
+    #[arg(long, requires = "replay")]
+    replay_loop: bool,
+
+    /// Generate fake advertisements instead of scanning (e.g. "ibeacon=10,bthome=5,random=100")
+    #[arg(long, value_name = "POPULATION", value_parser = parse_population, conflicts_with = "replay")]
+    synthetic: Option<Population>,
+
+    /// Synthetic advertisements per second, across all fake devices (0.1 to 1000000)
+    #[arg(long, default_value_t = 100.0, value_parser = parse_synthetic_rate, requires = "synthetic")]
+    synthetic_rate: f64,
+
+    /// Run simulated API clients and report throughput, lag and drops
+    #[arg(long, requires = "synthetic")]
+    bench: bool,
+
+    /// Number of simulated clients in bench mode
+    #[arg(long, default_value_t = 1, requires = "bench")]
+    bench_clients: usize,
+
+    /// Seconds between bench reports
+    #[arg(long, default_value_t = 5, requires = "bench")]
+    bench_interval: u64,
+}
+
+/// Fills in settings from the configuration file that weren't given on the
+/// command line (or in the environment). Returns the ones that were, which
+/// the file can't change.
+fn apply_config(cli: &mut Cli, config: &Config, matches: &ArgMatches) -> HashSet<&'static str> {
+    let mut overridden = HashSet::new();
+    let mut given = |id: &'static str| {
+        let given = matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        );
+        if given {
+            overridden.insert(id);
+        }
+        given
+    };
+    macro_rules! apply {
+        ($field:ident) => {
+            if !given(stringify!($field)) {
+                if let Some(value) = config.$field.clone() {
+                    cli.$field = value;
+                }
+            }
+        };
+        ($field:ident?) => {
+            if !given(stringify!($field)) && config.$field.is_some() {
+                cli.$field = config.$field.clone();
+            }
+        };
+    }
+    apply!(hci);
+    apply!(listen);
+    apply!(http_listen?);
+    apply!(hostname);
+    if !given("mac") {
+        cli.mac = config.mac.map(|mac| mac.0).or(cli.mac);
+    }
+    // Plaintext and a key exclude each other, so either on the command line
+    // also decides the other
+    if !given("plaintext") {
+        apply!(encryption_key_file?);
+    }
+    if !given("encryption_key_file") {
+        apply!(plaintext);
+    }
+    apply!(password_file?);
+    apply!(state_dir);
+    apply!(keepalive_interval);
+    apply!(keepalive_misses);
+    apply!(max_clock_skew);
+    apply!(host_sensors);
+    if !given("watch") {
+        if let Some(watch) = &config.watch {
+            cli.watch = watch.iter().map(|mac| mac.0).collect();
+        }
+    }
+    apply!(watch_timeout);
+    apply!(min_rssi?);
+    apply!(rules);
+    apply!(services);
+    overridden
+}
+
+/// Starts a graceful shutdown on SIGTERM or SIGINT; a second signal exits
+/// right away.
+async fn handle_shutdown_signals(ctx: Arc<ProxyContext>) -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+        if *ctx.shutdown.borrow() {
+            warn!("Exiting without waiting for clients");
+            std::process::exit(1);
+        }
+        info!("Shutting down");
+        ctx.shut_down(false);
+    }
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
-    let cli = Cli::parse();
+    logs::init();
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    let config = match &cli.config {
+        Some(path) => Config::load(path).unwrap_or_else(|e| {
+            log::error!("{e:#}");
+            log::error!("Fatal: Fix the configuration file, or leave out --config.");
+            std::process::exit(1);
+        }),
+        None => Config::default(),
+    };
+    let mut overridden = apply_config(&mut cli, &config, &matches);
+    if std::env::var_os("RUST_LOG").is_some() {
+        overridden.insert("log_level");
+    } else if config.log_level.is_some() {
+        logs::set_filter(config.log_level.as_deref());
+    }
+
+    // Replayed and synthetic advertisements don't need an adapter
+    let virtual_adapter = cli.replay.is_some() || cli.synthetic.is_some();
 
     // Validate HCI adapter exists before proceeding
-    if utils::get_bt_mac(cli.hci).is_none() {
+    if !virtual_adapter && utils::get_bt_mac(cli.hci).is_none() {
         log::error!(
             "Bluetooth adapter hci{} does not exist or is not accessible",
             cli.hci
@@ -75,39 +320,229 @@ async fn main() -> std::io::Result<()> {
         },
     };
 
-    let bt_mac = match utils::get_bt_mac(cli.hci) {
-        Some(mac) => mac,
-        None => {
-            log::error!("Failed to get Bluetooth MAC for hci{}", cli.hci);
-            log::error!("Fatal: Cannot access Bluetooth adapter hci{}. Check if it exists and is accessible.", cli.hci);
+    let bt_mac = if virtual_adapter {
+        // Borrow the network MAC for the virtual adapter
+        mac
+    } else {
+        match utils::get_bt_mac(cli.hci) {
+            Some(mac) => mac,
+            None => {
+                log::error!("Failed to get Bluetooth MAC for hci{}", cli.hci);
+                log::error!("Fatal: Cannot access Bluetooth adapter hci{}. Check if it exists and is accessible.", cli.hci);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    let state = StateStore::new(cli.state_dir.clone());
+    let provisioned_key = state.load_noise_key().unwrap_or_else(|e| {
+        warn!("Ignoring provisioned encryption key: {e:#}");
+        None
+    });
+
+    // A key provisioned by Home Assistant wins over the configured one, as on
+    // ESPHome; but plaintext, when asked for explicitly, wins over both
+    let encryption_key = match (&cli.encryption_key_file, cli.plaintext) {
+        (None, true) => {
+            if provisioned_key.is_some() {
+                warn!("Ignoring the API encryption key provisioned by Home Assistant, as --plaintext was given");
+            }
+            warn!("API encryption disabled, clients connect in plaintext");
+            None
+        }
+        _ if provisioned_key.is_some() => {
+            info!("Using API encryption key provisioned by Home Assistant");
+            provisioned_key
+        }
+        (Some(path), _) => match noise::load_psk(path) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                log::error!("{e:#}");
+                log::error!("Fatal: Cannot load API encryption key.");
+                std::process::exit(1);
+            }
+        },
+        (None, false) => {
+            log::error!("No API encryption key configured");
+            log::error!(
+                "Fatal: Pass --encryption-key-file, or --plaintext to serve the API unencrypted."
+            );
             std::process::exit(1);
         }
     };
 
+    let scan_settings = state.load_scan_settings().unwrap_or_else(|e| {
+        warn!("Ignoring saved scan settings: {e:#}");
+        None
+    });
+
+    // Deliberately not a plain argument: argv is visible to every local user
+    let password = match &cli.password_file {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(password) => Some(password.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) => {
+                log::error!("Failed to read API password from {}: {e}", path.display());
+                log::error!("Fatal: Cannot load API password.");
+                std::process::exit(1);
+            }
+        },
+        None => std::env::var("LINUX_BT_PROXY_PASSWORD").ok(),
+    }
+    .filter(|password| !password.is_empty());
+
+    // Bound before announcing it, so Home Assistant never finds the port
+    // closed; under systemd socket activation the socket is passed in instead
+    let (listener, listen_fd) = match server::activated_listener() {
+        Ok(Some(listener)) => {
+            let fd = listener.as_raw_fd();
+            (listener, Some(fd))
+        }
+        Ok(None) => match server::bind(cli.listen).await {
+            Ok(listener) => (listener, None),
+            Err(e) => {
+                log::error!("Failed to listen on {}: {e}", cli.listen);
+                log::error!("Fatal: TCP server failed to start.");
+                std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            log::error!("Unusable socket from systemd: {e}");
+            log::error!("Fatal: TCP server failed to start.");
+            std::process::exit(1);
+        }
+    };
+    let port = listener.local_addr()?.port();
+    let http_listener = match cli.http_listen {
+        Some(addr) => match http::bind(addr).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                log::error!("Failed to serve HTTP on {addr}: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let (ble_command_tx, ble_command_rx) = mpsc::channel(8);
+
     let ctx = Arc::new(ProxyContext {
         hostname: cli.hostname,
-        port: cli.listen.port(),
+        port,
+        webserver_port: match &http_listener {
+            Some(listener) => Some(listener.local_addr()?.port()),
+            None => None,
+        },
         net_mac: mac,
         bt_mac,
         build_time: env!("BUILD_TIME"),
         version: env!("CARGO_PKG_VERSION"),
+        encryption_key: RwLock::new(encryption_key),
+        state,
+        password,
+        outbound: Arc::default(),
+        keepalive_interval: Duration::from_secs(cli.keepalive_interval),
+        keepalive_misses: cli.keepalive_misses,
+        advertisement_source: match (&cli.replay, &cli.synthetic) {
+            (Some(path), _) => format!("replay of {}", path.display()),
+            (None, Some(population)) => format!("synthetic {population:?}"),
+            (None, None) => format!("hci{}", cli.hci),
+        },
+        max_clock_skew: (cli.max_clock_skew > 0).then(|| Duration::from_secs(cli.max_clock_skew)),
+        started: std::time::Instant::now(),
+        api_clients: AtomicUsize::new(0),
+        clients: Mutex::default(),
+        tracker: Arc::default(),
+        // Only a real adapter can be told what to do
+        ble_commands: (!virtual_adapter).then_some(ble_command_tx),
+        ble_stats: Arc::default(),
+        scan_settings: watch::Sender::new(scan_settings.unwrap_or_default()),
+        host_sensors: cli.host_sensors,
+        watched: cli.watch.iter().map(|&mac| mac_to_address(mac)).collect(),
+        watch_timeout: Duration::from_secs(cli.watch_timeout),
+        presence_events: broadcast::channel(16).0,
+        rules: Rules::new(cli.rules),
+        min_rssi: RwLock::new(cli.min_rssi),
+        services: cli.services,
+        shutdown: watch::Sender::new(false),
+        restart: AtomicBool::new(false),
+        listen_fd,
     });
 
-    let (tx, rx) = broadcast::channel(100);
+    let signal_ctx = ctx.clone();
+    tokio::spawn(async move {
+        if let Err(e) = handle_shutdown_signals(signal_ctx).await {
+            warn!("Cannot shut down gracefully on SIGTERM: {e}");
+        }
+    });
+
+    tokio::spawn(config::run_reload(
+        ctx.clone(),
+        cli.config.clone(),
+        config,
+        overridden,
+    ));
+
+    // Client tasks only queue what they receive, so this just has to absorb
+    // scheduling hiccups; slow clients are handled by their outbound queue
+    let (tx, rx) = broadcast::channel(1024);
+    tokio::spawn(tracker::run_tracker(ctx.tracker.clone(), tx.subscribe()));
+    if !ctx.watched.is_empty() {
+        tokio::spawn(presence::run_presence(ctx.clone(), tx.subscribe()));
+    }
 
-    // first cut: use bluez stack, ask for active scanning
-    let mut ble_handle = tokio::spawn(ble::run_bluez_advertisement_listener(cli.hci, tx.clone()));
+    let mut ble_handle = if let Some(path) = &cli.replay {
+        tokio::spawn(replay::run_replay_source(
+            path.clone(),
+            cli.replay_speed,
+            cli.replay_loop,
+            tx.clone(),
+        ))
+    } else if let Some(population) = &cli.synthetic {
+        let stats = Arc::new(SyntheticStats::default());
+        if cli.bench {
+            tokio::spawn(synthetic::run_bench(
+                cli.bench_clients,
+                Duration::from_secs(cli.bench_interval.max(1)),
+                stats.clone(),
+                tx.clone(),
+            ));
+        }
+        tokio::spawn(synthetic::run_synthetic_source(
+            population.clone(),
+            cli.synthetic_rate,
+            stats,
+            tx.clone(),
+        ))
+    } else {
+        // first cut: use bluez stack, ask for active scanning
+        let tx = tx.clone();
+        let tracker = ctx.tracker.clone();
+        let settings = ctx.scan_settings.subscribe();
+        let stats = ctx.ble_stats.clone();
+        tokio::spawn(async move {
+            ble::run_bluez_advertisement_listener(
+                cli.hci,
+                tx,
+                tracker,
+                ble_command_rx,
+                settings,
+                stats,
+            )
+            .await
+            .map_err(anyhow::Error::from)
+        })
+    };
 
     // Check if BLE listener started successfully
     tokio::select! {
-        _ = tokio::time::sleep(tokio::time::Duration::from_millis(100)) => {
+        _ = tokio::time::sleep(Duration::from_millis(100)) => {
             // BLE listener is still running after 100ms, assume it started successfully
         }
         result = &mut ble_handle => {
             match result {
                 Ok(Err(e)) => {
-                    log::error!("Failed to start BLE advertisement listener: {e}");
-                    log::error!("Fatal: Cannot connect to BlueZ D-Bus service. Check if bluetoothd is running.");
+                    log::error!("Failed to start BLE advertisement listener: {e:#}");
+                    log::error!("Fatal: Cannot start advertisement source. Check if bluetoothd is running or the capture file is valid.");
                     std::process::exit(1);
                 }
                 Err(e) => {
@@ -124,20 +559,162 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
-    info!("Listening for ble advertisements on hci{}", cli.hci);
+    if !virtual_adapter {
+        info!("Listening for ble advertisements on hci{}", cli.hci);
+    }
 
-    mdns::start_mdns(ctx.clone()).unwrap_or_else(|e| {
+    let mdns = mdns::start_mdns(ctx.clone()).unwrap_or_else(|e| {
         warn!("Critical error: failed to register mDNS service: {e}");
         std::process::exit(1);
     });
 
     info!("mDNS service registered");
 
-    if let Err(e) = server::run_tcp_server(ctx.clone(), cli.listen, rx).await {
+    if let Some(listener) = http_listener {
+        tokio::spawn(http::run_http_server(ctx.clone(), listener));
+    }
+
+    systemd::notify_ready();
+    tokio::spawn(systemd::run_notify(ctx.clone()));
+
+    if let Err(e) = server::run_tcp_server(ctx.clone(), listener, rx).await {
         log::error!("TCP server error: {e}");
         log::error!("Fatal: TCP server failed to start or crashed.");
         std::process::exit(1);
     }
 
+    // Clients are gone; leave nothing running in bluetoothd or announced on
+    // the network. The proxy makes no connections to devices, so there are
+    // no links to close.
+    if let Some(commands) = &ctx.ble_commands {
+        if commands.send(BleCommand::Shutdown).await.is_ok() {
+            match timeout(SCANNER_STOP_TIMEOUT, ble_handle).await {
+                Ok(Ok(Err(e))) => warn!("BLE advertisement listener failed while stopping: {e}"),
+                Err(_) => warn!("Timed out stopping the scanner"),
+                _ => {}
+            }
+        }
+    }
+    mdns.unregister();
+    info!("Shut down");
+
+    if ctx.restart.load(Ordering::Relaxed) {
+        info!("Restarting");
+        let e = reexec(ctx.listen_fd);
+        // Under systemd, Restart=on-failure picks it up from here
+        log::error!("Failed to restart: {e}");
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_config(args: &[&str], config: &str) -> (Cli, HashSet<&'static str>) {
+        // Whatever the environment running the tests holds mustn't count
+        let command = Cli::command().mut_args(|arg| arg.env(None));
+        apply_matches(command, args, config)
+    }
+
+    fn apply_matches(
+        command: clap::Command,
+        args: &[&str],
+        config: &str,
+    ) -> (Cli, HashSet<&'static str>) {
+        let matches = command
+            .try_get_matches_from(std::iter::once("linux_bt_proxy").chain(args.iter().copied()))
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        let overridden = apply_config(&mut cli, &config.parse().unwrap(), &matches);
+        (cli, overridden)
+    }
+
+    #[test]
+    fn file_fills_in_what_the_command_line_leaves_out() {
+        let (cli, overridden) = with_config(
+            &[],
+            "hci = 2\nkeepalive_misses = 5\nmin_rssi = -80\nwatch = [\"AA:BB:CC:DD:EE:01\"]",
+        );
+        assert_eq!(cli.hci, 2);
+        assert_eq!(cli.keepalive_misses, 5);
+        assert_eq!(cli.min_rssi, Some(-80));
+        assert_eq!(cli.watch, [[0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x01]]);
+        // Untouched defaults
+        assert_eq!(cli.keepalive_interval, 60);
+        assert!(overridden.is_empty());
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let (cli, overridden) = with_config(
+            &[
+                "--hci",
+                "1",
+                "--min-rssi",
+                "-90",
+                "--mac",
+                "02:00:00:00:00:01",
+            ],
+            "hci = 2\nmin_rssi = -80\nmac = \"02:00:00:00:00:02\"\nkeepalive_misses = 5",
+        );
+        assert_eq!(cli.hci, 1);
+        assert_eq!(cli.min_rssi, Some(-90));
+        assert_eq!(cli.mac, Some([2, 0, 0, 0, 0, 1]));
+        assert_eq!(cli.keepalive_misses, 5);
+        assert_eq!(overridden, HashSet::from(["hci", "min_rssi", "mac"]));
+    }
+
+    #[test]
+    fn environment_wins() {
+        // Setting a variable would race the other tests, so the check runs
+        // again in a process of its own with the variable given
+        const ALONE: &str = "LINUX_BT_PROXY_TEST_ALONE";
+        if std::env::var_os(ALONE).is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tests::environment_wins"])
+                .env(ALONE, "1")
+                .env("LINUX_BT_PROXY_PASSWORD_FILE", "/run/secrets/password")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success(), "{stdout}");
+            assert!(stdout.contains("1 passed"), "{stdout}");
+            return;
+        }
+        let (cli, overridden) =
+            apply_matches(Cli::command(), &[], "password_file = \"/etc/password\"");
+        assert_eq!(cli.password_file, Some("/run/secrets/password".into()));
+        assert!(overridden.contains("password_file"));
+    }
+
+    #[test]
+    fn plaintext_on_the_command_line_ignores_the_files_key() {
+        let (cli, overridden) = with_config(
+            &["--plaintext"],
+            "encryption_key_file = \"/etc/linux-bt-proxy/api.key\"",
+        );
+        assert!(cli.plaintext);
+        assert_eq!(cli.encryption_key_file, None);
+        assert!(overridden.contains("plaintext"));
+    }
+
+    #[test]
+    fn a_key_on_the_command_line_ignores_the_files_plaintext() {
+        let (cli, overridden) = with_config(
+            &["--encryption-key-file", "/run/api.key"],
+            "plaintext = true",
+        );
+        assert!(!cli.plaintext);
+        assert_eq!(cli.encryption_key_file, Some("/run/api.key".into()));
+        assert!(overridden.contains("encryption_key_file"));
+    }
+
+    #[test]
+    fn defaults_given_explicitly_still_win() {
+        let (cli, overridden) = with_config(&["--hci", "0"], "hci = 2");
+        assert_eq!(cli.hci, 0);
+        assert!(overridden.contains("hci"));
+    }
+}
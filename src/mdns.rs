@@ -1,12 +1,37 @@
 use anyhow::Result;
-use log::info;
-use mdns_sd::{ServiceDaemon, ServiceInfo};
+use log::{info, warn};
+use mdns_sd::{ServiceDaemon, ServiceInfo, UnregisterStatus};
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::context::ProxyContext;
 use crate::utils::format_mac;
 
-pub fn start_mdns(ctx: Arc<ProxyContext>) -> Result<()> {
+// How long to wait for the goodbye packets announcing the service is gone
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The registered service, to take back on shutdown so Home Assistant
+/// doesn't show the proxy until the record expires.
+pub struct MdnsRegistration {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl MdnsRegistration {
+    pub fn unregister(self) {
+        match self.daemon.unregister(&self.fullname) {
+            Ok(status) => match status.recv_timeout(UNREGISTER_TIMEOUT) {
+                Ok(UnregisterStatus::OK) => info!("mDNS service unregistered"),
+                Ok(UnregisterStatus::NotFound) => warn!("mDNS service was not registered"),
+                Err(e) => warn!("Failed to unregister mDNS service: {e}"),
+            },
+            Err(e) => warn!("Failed to unregister mDNS service: {e}"),
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+pub fn start_mdns(ctx: Arc<ProxyContext>) -> Result<MdnsRegistration> {
     let mdns = ServiceDaemon::new().expect("Failed to create mDNS daemon");
 
     let mac = format_mac(&ctx.bt_mac, ":");
@@ -37,6 +62,7 @@ pub fn start_mdns(ctx: Arc<ProxyContext>) -> Result<()> {
     )
     .expect("Invalid service info")
     .enable_addr_auto();
+    let fullname = my_service.get_fullname().to_string();
 
     mdns.register(my_service)
         .expect("Failed to register mDNS service");
@@ -45,5 +71,8 @@ pub fn start_mdns(ctx: Arc<ProxyContext>) -> Result<()> {
         "mDNS service registered for {} on port {} with MAC {}",
         ctx.hostname, ctx.port, mac
     );
-    Ok(())
+    Ok(MdnsRegistration {
+        daemon: mdns,
+        fullname,
+    })
 }
@@ -0,0 +1,258 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::context::ProxyContext;
+
+const PREFIX: &str = "linux_bt_proxy_";
+
+/// Prometheus text exposition format, version 0.0.4.
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {PREFIX}{name} {help}");
+        let _ = writeln!(self.0, "# TYPE {PREFIX}{name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let _ = write!(self.0, "{PREFIX}{name}");
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {value}");
+    }
+
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Everything the proxy counts, for `/metrics`.
+pub fn render(ctx: &ProxyContext) -> String {
+    let mut out = Exposition(String::new());
+    let adapter = [("adapter", ctx.advertisement_source.as_str())];
+    let outbound = &ctx.outbound;
+
+    out.family(
+        "advertisements_received_total",
+        "counter",
+        "Advertisements heard from the adapter.",
+    );
+    out.sample(
+        "advertisements_received_total",
+        &adapter,
+        ctx.tracker.adverts_received(),
+    );
+    out.family(
+        "advertisements_forwarded_total",
+        "counter",
+        "Advertisements queued for subscribed clients, once per client.",
+    );
+    out.sample(
+        "advertisements_forwarded_total",
+        &adapter,
+        load(&outbound.adverts_forwarded),
+    );
+    out.family(
+        "advertisements_filtered_total",
+        "counter",
+        "Advertisements not forwarded for being below the minimum RSSI, once per client.",
+    );
+    out.sample(
+        "advertisements_filtered_total",
+        &adapter,
+        load(&outbound.adverts_filtered),
+    );
+    out.single(
+        "advertisements_coalesced_total",
+        "counter",
+        "Queued advertisements replaced by a repeat from the same device, differing only in RSSI.",
+        load(&outbound.adverts_coalesced),
+    );
+    out.single(
+        "advertisements_dropped_total",
+        "counter",
+        "Advertisements a client never got, for a full queue or broadcast lag.",
+        load(&outbound.adverts_dropped),
+    );
+    out.single(
+        "broadcast_lagged_total",
+        "counter",
+        "Advertisements client tasks missed by falling behind the broadcast channel.",
+        load(&outbound.adverts_lagged),
+    );
+    out.single(
+        "slow_clients_disconnected_total",
+        "counter",
+        "Clients disconnected for not keeping up.",
+        load(&outbound.slow_clients_disconnected),
+    );
+    out.single(
+        "outbound_queued_bytes",
+        "gauge",
+        "Bytes waiting to be written, over all clients.",
+        load(&outbound.queued_bytes),
+    );
+    out.single(
+        "dbus_errors_total",
+        "counter",
+        "Failed BlueZ calls.",
+        load(&ctx.ble_stats.dbus_errors),
+    );
+    out.single(
+        "discovery_restarts_total",
+        "counter",
+        "Times discovery was restarted, on request or after BlueZ stopped it.",
+        load(&ctx.ble_stats.discovery_restarts),
+    );
+    out.single(
+        "known_devices",
+        "gauge",
+        "Devices heard in the last five minutes.",
+        ctx.tracker.known_devices(),
+    );
+
+    out.single(
+        "api_clients",
+        "gauge",
+        "Connected API clients.",
+        ctx.api_clients.load(Ordering::Relaxed),
+    );
+    out.family(
+        "client_bytes_sent_total",
+        "counter",
+        "Bytes written to each connected API client.",
+    );
+    let mut clients: Vec<(String, String, u64)> = ctx
+        .clients
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(peer, client)| {
+            (
+                peer.to_string(),
+                client.name.clone(),
+                client.outbound.bytes_sent(),
+            )
+        })
+        .collect();
+    clients.sort();
+    for (peer, name, bytes) in &clients {
+        out.sample(
+            "client_bytes_sent_total",
+            &[("client", peer), ("name", name)],
+            bytes,
+        );
+    }
+
+    // The proxy only listens; it has no connection slots to hand out
+    out.single(
+        "bluetooth_connections_free",
+        "gauge",
+        "Free active connection slots.",
+        0,
+    );
+    out.single(
+        "bluetooth_connections_limit",
+        "gauge",
+        "Active connection slots.",
+        0,
+    );
+    out.single(
+        "uptime_seconds",
+        "gauge",
+        "Seconds since the proxy started.",
+        ctx.started.elapsed().as_secs(),
+    );
+    out.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ClientInfo;
+    use crate::outbound::Outbound;
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    fn with_client(ctx: &ProxyContext, peer: &str, name: &str) {
+        let peer: SocketAddr = peer.parse().unwrap();
+        ctx.clients.lock().unwrap().insert(
+            peer,
+            ClientInfo {
+                name: name.to_string(),
+                since: Instant::now(),
+                outbound: Arc::new(Outbound::new(peer, ctx.outbound.clone())),
+            },
+        );
+    }
+
+    #[test]
+    fn escapes_client_names() {
+        let ctx = ProxyContext::for_tests();
+        with_client(&ctx, "192.0.2.1:40000", "Home \"Assistant\"\nC:\\ha");
+        with_client(&ctx, "[2001:db8::1]:40001", "aioesphomeapi");
+        let text = render(&ctx);
+
+        let samples: Vec<&str> = text
+            .lines()
+            .filter(|line| line.starts_with("linux_bt_proxy_client_bytes_sent_total"))
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                r#"linux_bt_proxy_client_bytes_sent_total{client="192.0.2.1:40000",name="Home \"Assistant\"\nC:\\ha"} 0"#,
+                r#"linux_bt_proxy_client_bytes_sent_total{client="[2001:db8::1]:40001",name="aioesphomeapi"} 0"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn every_sample_has_help_and_type() {
+        let ctx = ProxyContext::for_tests();
+        with_client(&ctx, "192.0.2.1:40000", "Home Assistant");
+        let text = render(&ctx);
+
+        let mut described = HashSet::new();
+        let mut lines = text.lines();
+        while let Some(line) = lines.next() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                let (name, help) = help.split_once(' ').unwrap();
+                assert!(!help.is_empty(), "{line}");
+                let kind = lines.next().unwrap();
+                assert!(
+                    kind == format!("# TYPE {name} counter")
+                        || kind == format!("# TYPE {name} gauge"),
+                    "{kind}"
+                );
+                assert!(described.insert(name), "{name} described twice");
+                continue;
+            }
+            let name = line.split(['{', ' ']).next().unwrap();
+            assert!(described.contains(name), "{name} has no HELP and TYPE");
+            assert!(
+                line.rsplit(' ').next().unwrap().parse::<f64>().is_ok(),
+                "{line}"
+            );
+        }
+        assert!(described.contains("linux_bt_proxy_uptime_seconds"));
+    }
+}
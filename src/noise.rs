@@ -0,0 +1,184 @@
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Bytes, BytesMut};
+use log::{debug, warn};
+use snow::{Builder, StatelessTransportState};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::context::ProxyContext;
+use crate::proto::{decode_frame, encode_noise_frame, Frame, NOISE_PREAMBLE};
+use crate::utils::format_mac;
+
+// Same protocol name and prologue as ESPHome's api component
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+const NOISE_PROLOGUE: &[u8] = b"NoiseAPIInit\x00\x00";
+
+// Largest Noise message, and the AEAD tag each transport message carries
+const NOISE_MAX_MESSAGE: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+
+/// Decodes a base64 pre-shared key in the format ESPHome generates.
+pub fn decode_psk(encoded: &str) -> Result<[u8; 32]> {
+    let key = STANDARD
+        .decode(encoded.trim())
+        .context("Encryption key is not valid base64")?;
+    match key.try_into() {
+        Ok(key) => Ok(key),
+        Err(key) => bail!("Encryption key must be 32 bytes, got {}", key.len()),
+    }
+}
+
+pub fn load_psk(path: &Path) -> Result<[u8; 32]> {
+    let encoded = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading encryption key from {}", path.display()))?;
+    decode_psk(&encoded).with_context(|| format!("Invalid encryption key in {}", path.display()))
+}
+
+fn noise_error(e: snow::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Noise error: {e}"))
+}
+
+/// Reads the next Noise frame during the handshake, when nothing else is
+/// happening on the connection.
+async fn read_handshake_frame(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<Bytes, Error> {
+    loop {
+        if let Some(&preamble) = buf.first() {
+            if preamble != NOISE_PREAMBLE {
+                send_handshake_reject(stream, "Bad indicator byte").await;
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Bad indicator byte 0x{preamble:02x}, client is not using encryption"),
+                ));
+            }
+        }
+        if let Some(Frame::Noise(frame)) = decode_frame(buf)? {
+            return Ok(frame);
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed during handshake",
+            ));
+        }
+    }
+}
+
+/// Tells the client why the handshake failed, in the form aioesphomeapi
+/// turns into a readable error (e.g. "Handshake MAC failure" for a wrong key).
+async fn send_handshake_reject(stream: &mut TcpStream, reason: &str) {
+    let mut payload = vec![0x01];
+    payload.extend_from_slice(reason.as_bytes());
+    if let Err(e) = stream.write_all(&encode_noise_frame(&payload)).await {
+        debug!("Failed to send handshake reject: {e}");
+    }
+}
+
+/// Runs the responder side of the ESPHome Noise handshake: client hello,
+/// server hello (node name and MAC), then the NNpsk0 exchange.
+pub async fn server_handshake(
+    ctx: &ProxyContext,
+    psk: &[u8; 32],
+    stream: &mut TcpStream,
+    buf: &mut BytesMut,
+) -> Result<StatelessTransportState, Error> {
+    // Client hello carries no data we use
+    read_handshake_frame(stream, buf).await?;
+
+    // Server hello: chosen protocol, node name, MAC, each name NUL terminated
+    let mut hello = vec![NOISE_PREAMBLE];
+    hello.extend_from_slice(ctx.device_name().as_bytes());
+    hello.push(0x00);
+    hello.extend_from_slice(format_mac(&ctx.net_mac, "").to_lowercase().as_bytes());
+    hello.push(0x00);
+    stream.write_all(&encode_noise_frame(&hello)).await?;
+
+    let mut handshake = Builder::new(NOISE_PARAMS.parse().map_err(noise_error)?)
+        .psk(0, psk)
+        .prologue(NOISE_PROLOGUE)
+        .build_responder()
+        .map_err(noise_error)?;
+
+    let frame = read_handshake_frame(stream, buf).await?;
+    match frame.split_first() {
+        Some((0x00, message)) => {
+            let mut scratch = vec![0u8; NOISE_MAX_MESSAGE];
+            if let Err(e) = handshake.read_message(message, &mut scratch) {
+                // Almost always a mismatched pre-shared key
+                warn!("Noise handshake failed: {e}");
+                send_handshake_reject(stream, "Handshake MAC failure").await;
+                return Err(noise_error(e));
+            }
+        }
+        _ => {
+            send_handshake_reject(stream, "Bad handshake packet").await;
+            return Err(Error::new(ErrorKind::InvalidData, "Bad handshake packet"));
+        }
+    }
+
+    let mut reply = vec![0u8; NOISE_MAX_MESSAGE];
+    let len = handshake
+        .write_message(&[], &mut reply[1..])
+        .map_err(noise_error)?;
+    reply.truncate(1 + len); // reply[0] stays 0x00: success
+    stream.write_all(&encode_noise_frame(&reply)).await?;
+
+    handshake
+        .into_stateless_transport_mode()
+        .map_err(noise_error)
+}
+
+/// Encrypts one API message into a complete Noise frame. The plaintext is the
+/// message type and payload length (both big-endian u16) followed by the payload.
+/// Each direction counts its own nonces, starting at 0 after the handshake.
+pub fn encrypt_message(
+    transport: &StatelessTransportState,
+    nonce: u64,
+    msg_type: u32,
+    payload: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if 4 + payload.len() + NOISE_TAG_LEN > NOISE_MAX_MESSAGE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Message too large for a Noise frame",
+        ));
+    }
+
+    let mut plaintext = Vec::with_capacity(4 + payload.len());
+    plaintext.extend_from_slice(&(msg_type as u16).to_be_bytes());
+    plaintext.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    plaintext.extend_from_slice(payload);
+
+    let mut ciphertext = vec![0u8; plaintext.len() + NOISE_TAG_LEN];
+    let len = transport
+        .write_message(nonce, &plaintext, &mut ciphertext)
+        .map_err(noise_error)?;
+    Ok(encode_noise_frame(&ciphertext[..len]))
+}
+
+/// Decrypts the payload of a Noise frame into a message type and payload.
+pub fn decrypt_message(
+    transport: &StatelessTransportState,
+    nonce: u64,
+    frame: &[u8],
+) -> Result<(u32, Bytes), Error> {
+    let mut plaintext = vec![0u8; frame.len()];
+    let len = transport
+        .read_message(nonce, frame, &mut plaintext)
+        .map_err(noise_error)?;
+    if len < 4 {
+        return Err(Error::new(ErrorKind::InvalidData, "Short Noise message"));
+    }
+    let msg_type = u16::from_be_bytes([plaintext[0], plaintext[1]]) as u32;
+    let data_len = u16::from_be_bytes([plaintext[2], plaintext[3]]) as usize;
+    if 4 + data_len > len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Noise message length exceeds frame",
+        ));
+    }
+    plaintext.truncate(4 + data_len);
+    Ok((msg_type, Bytes::from(plaintext).slice(4..)))
+}
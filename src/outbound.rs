@@ -0,0 +1,446 @@
+use log::{debug, info, warn};
+use snow::StatelessTransportState;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::Notify;
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::api::api::BluetoothLEAdvertisementResponse;
+use crate::dispatch::message_info;
+use crate::noise;
+use crate::proto::encode_plaintext_frame;
+
+/// Queued bytes per client above which advertisements are dropped. Replies
+/// to the client's own requests are always queued.
+pub const OUTBOUND_LIMIT: usize = 128 * 1024;
+
+/// How long a client may stay over the limit, or take to accept one write,
+/// before it's disconnected as a slow consumer.
+pub const SLOW_CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outbound queue counters across all clients.
+#[derive(Default)]
+pub struct OutboundStats {
+    /// Bytes currently queued, summed over clients
+    pub queued_bytes: AtomicU64,
+    /// Advertisements replaced before they were written by a newer one from
+    /// the same device, the same but for its RSSI
+    pub adverts_coalesced: AtomicU64,
+    /// Advertisements a client never got: its queue was full, or its task
+    /// fell behind the broadcast channel
+    pub adverts_dropped: AtomicU64,
+    /// The part of `adverts_dropped` lost to the broadcast channel
+    pub adverts_lagged: AtomicU64,
+    /// Advertisements queued for a subscribed client, counted per client
+    pub adverts_forwarded: AtomicU64,
+    /// Advertisements a subscribed client didn't get for being too weak
+    pub adverts_filtered: AtomicU64,
+    pub slow_clients_disconnected: AtomicU64,
+}
+
+enum Pending {
+    Message {
+        msg_type: u32,
+        payload: Vec<u8>,
+    },
+    /// An advertisement kept in `Queue::adverts`, by its slot
+    Advert(u64),
+    /// Already framed bytes, sent as is
+    Raw(Vec<u8>),
+}
+
+/// A queued advertisement, the latest from its device.
+struct Waiting {
+    advert: BluetoothLEAdvertisementResponse,
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<Pending>,
+    /// Queued advertisements by slot, and the slot of each device's latest
+    adverts: HashMap<u64, Waiting>,
+    latest: HashMap<u64, u64>,
+    next_slot: u64,
+    bytes: usize,
+    over_limit_since: Option<Instant>,
+    closed: bool,
+    failed: bool,
+    // For the summary logged when the connection ends
+    peak_bytes: usize,
+    coalesced: u64,
+    dropped: u64,
+}
+
+/// Messages waiting for a client's writer task. The connection task only
+/// ever queues, so a client that reads slowly can't hold up its requests,
+/// and the broadcast of advertisements never waits on it.
+pub struct Outbound {
+    peer: SocketAddr,
+    stats: Arc<OutboundStats>,
+    queue: Mutex<Queue>,
+    /// Wakes the writer: something urgent was queued, or the queue closed
+    wake: Notify,
+    /// Wakes the connection task when the writer gives up
+    failed: Notify,
+    /// Written to the socket so far
+    bytes_sent: AtomicU64,
+}
+
+impl Outbound {
+    pub fn new(peer: SocketAddr, stats: Arc<OutboundStats>) -> Self {
+        Outbound {
+            peer,
+            stats,
+            queue: Mutex::new(Queue::default()),
+            wake: Notify::new(),
+            failed: Notify::new(),
+            bytes_sent: AtomicU64::new(0),
+        }
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Queues a message. Urgent (`no_delay`) messages wake the writer at
+    /// once; others wait for the next `flush`.
+    pub fn push(&self, msg_type: u32, payload: Vec<u8>, urgent: bool) -> Result<(), Error> {
+        let mut queue = self.lock()?;
+        queue.add_bytes(&self.stats, payload.len());
+        queue
+            .pending
+            .push_back(Pending::Message { msg_type, payload });
+        self.check_slow(&mut queue)?;
+        drop(queue);
+        if urgent {
+            self.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    pub fn push_raw(&self, frame: Vec<u8>) -> Result<(), Error> {
+        let mut queue = self.lock()?;
+        queue.add_bytes(&self.stats, frame.len());
+        queue.pending.push_back(Pending::Raw(frame));
+        drop(queue);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Queues an advertisement. If the device's last one is still waiting
+    /// and only its RSSI differs, it's updated rather than sent twice;
+    /// anything else is queued on its own, so devices that rotate frames
+    /// lose none. When the queue is over the limit the advertisement is
+    /// dropped.
+    pub fn push_advert(&self, advert: &BluetoothLEAdvertisementResponse) -> Result<(), Error> {
+        let payload = protobuf::Message::write_to_bytes(advert)?;
+        let mut queue = self.lock()?;
+        let waiting_slot = queue.latest.get(&advert.address).copied().filter(|slot| {
+            queue
+                .adverts
+                .get(slot)
+                .is_some_and(|waiting| same_but_rssi(&waiting.advert, advert))
+        });
+        if let Some(slot) = waiting_slot {
+            let waiting = queue.adverts.get_mut(&slot).expect("checked above");
+            waiting.advert.rssi = advert.rssi;
+            let old_len = std::mem::replace(&mut waiting.payload, payload).len();
+            let new_len = waiting.payload.len();
+            queue.sub_bytes(&self.stats, old_len);
+            queue.add_bytes(&self.stats, new_len);
+            queue.coalesced += 1;
+            self.stats.adverts_coalesced.fetch_add(1, Ordering::Relaxed);
+        } else if queue.bytes >= OUTBOUND_LIMIT {
+            if queue.dropped == 0 {
+                warn!(
+                    "Outbound queue to {} is full, dropping advertisements",
+                    self.peer.ip()
+                );
+            }
+            queue.dropped += 1;
+            self.stats.adverts_dropped.fetch_add(1, Ordering::Relaxed);
+        } else {
+            queue.add_bytes(&self.stats, payload.len());
+            let slot = queue.next_slot;
+            queue.next_slot += 1;
+            queue.adverts.insert(
+                slot,
+                Waiting {
+                    advert: advert.clone(),
+                    payload,
+                },
+            );
+            queue.latest.insert(advert.address, slot);
+            queue.pending.push_back(Pending::Advert(slot));
+        }
+        self.check_slow(&mut queue)?;
+        drop(queue);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Hands everything queued so far to the writer.
+    pub fn flush(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Lets the writer send what's left, then shut the socket down.
+    pub fn close(&self) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.closed = true;
+        }
+        self.wake.notify_one();
+    }
+
+    /// Resolves once the writer has given up on the client.
+    pub async fn writer_failed(&self) {
+        let notified = self.failed.notified();
+        if self.queue.lock().map(|q| q.failed).unwrap_or(true) {
+            return;
+        }
+        notified.await
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Queue>, Error> {
+        let queue = self.queue.lock().unwrap();
+        if queue.failed {
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "Connection writer stopped",
+            ));
+        }
+        Ok(queue)
+    }
+
+    fn check_slow(&self, queue: &mut Queue) -> Result<(), Error> {
+        if queue.bytes < OUTBOUND_LIMIT {
+            queue.over_limit_since = None;
+            return Ok(());
+        }
+        let since = *queue.over_limit_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= SLOW_CLIENT_TIMEOUT {
+            self.stats
+                .slow_clients_disconnected
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "slow consumer, outbound queue over {OUTBOUND_LIMIT} bytes for {}s",
+                    SLOW_CLIENT_TIMEOUT.as_secs()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Waits for work and takes the whole queue, with the advertisements
+    /// it refers to. `None` once the queue is closed and empty.
+    async fn next_batch(&self) -> Option<(Vec<Pending>, HashMap<u64, Waiting>)> {
+        loop {
+            let notified = self.wake.notified();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if !queue.pending.is_empty() {
+                    let bytes = queue.bytes;
+                    queue.sub_bytes(&self.stats, bytes);
+                    let pending = queue.pending.drain(..).collect();
+                    queue.latest.clear();
+                    return Some((pending, std::mem::take(&mut queue.adverts)));
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn fail(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.failed = true;
+        let bytes = queue.bytes;
+        queue.sub_bytes(&self.stats, bytes);
+        drop(queue);
+        self.failed.notify_waiters();
+    }
+}
+
+impl Drop for Outbound {
+    // Once both the connection and its writer are done
+    fn drop(&mut self) {
+        let Ok(queue) = self.queue.get_mut() else {
+            return;
+        };
+        self.stats
+            .queued_bytes
+            .fetch_sub(queue.bytes as u64, Ordering::Relaxed);
+        if queue.dropped > 0 || queue.coalesced > 0 {
+            info!(
+                "Outbound to {}: {} advertisements coalesced, {} dropped, peak queue {} bytes",
+                self.peer.ip(),
+                queue.coalesced,
+                queue.dropped,
+                queue.peak_bytes
+            );
+        }
+    }
+}
+
+/// Whether `b` carries nothing new over `a`: the same device and data,
+/// heard again.
+fn same_but_rssi(
+    a: &BluetoothLEAdvertisementResponse,
+    b: &BluetoothLEAdvertisementResponse,
+) -> bool {
+    a.address == b.address
+        && a.address_type == b.address_type
+        && a.name == b.name
+        && a.service_uuids == b.service_uuids
+        && a.service_data == b.service_data
+        && a.manufacturer_data == b.manufacturer_data
+}
+
+impl Queue {
+    fn add_bytes(&mut self, stats: &OutboundStats, n: usize) {
+        self.bytes += n;
+        self.peak_bytes = self.peak_bytes.max(self.bytes);
+        stats.queued_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn sub_bytes(&mut self, stats: &OutboundStats, n: usize) {
+        self.bytes -= n;
+        stats.queued_bytes.fetch_sub(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Writes queued messages to the client until the queue is closed, framing
+/// (and with Noise, encrypting) each one. A backlog goes out in one write.
+pub async fn run_writer(
+    outbound: Arc<Outbound>,
+    mut stream: OwnedWriteHalf,
+    noise: Option<Arc<StatelessTransportState>>,
+) {
+    let advert_type = message_info::<BluetoothLEAdvertisementResponse>().id;
+    let mut nonce = 0u64;
+    let mut out = Vec::new();
+    while let Some((batch, mut adverts)) = outbound.next_batch().await {
+        out.clear();
+        for pending in batch {
+            let (msg_type, payload) = match pending {
+                Pending::Message { msg_type, payload } => (msg_type, payload),
+                Pending::Advert(slot) => match adverts.remove(&slot) {
+                    Some(waiting) => (advert_type, waiting.payload),
+                    None => continue,
+                },
+                Pending::Raw(frame) => {
+                    out.extend_from_slice(&frame);
+                    continue;
+                }
+            };
+            let frame = match &noise {
+                None => Ok(encode_plaintext_frame(msg_type, &payload)),
+                Some(transport) => {
+                    nonce += 1;
+                    noise::encrypt_message(transport, nonce - 1, msg_type, &payload)
+                }
+            };
+            match frame {
+                Ok(frame) => out.extend_from_slice(&frame),
+                Err(e) => {
+                    warn!("Failed to encode message for {}: {e}", outbound.peer.ip());
+                    outbound.fail();
+                    return;
+                }
+            }
+        }
+        match timeout(SLOW_CLIENT_TIMEOUT, stream.write_all(&out)).await {
+            Ok(Ok(())) => {
+                outbound
+                    .bytes_sent
+                    .fetch_add(out.len() as u64, Ordering::Relaxed);
+            }
+            Ok(Err(e)) => {
+                debug!("Write to {} failed: {e}", outbound.peer.ip());
+                outbound.fail();
+                return;
+            }
+            Err(_) => {
+                warn!(
+                    "{} accepted no data for {}s, disconnecting slow consumer",
+                    outbound.peer.ip(),
+                    SLOW_CLIENT_TIMEOUT.as_secs()
+                );
+                outbound
+                    .stats
+                    .slow_clients_disconnected
+                    .fetch_add(1, Ordering::Relaxed);
+                outbound.fail();
+                return;
+            }
+        }
+    }
+    let _ = timeout(SLOW_CLIENT_TIMEOUT, stream.shutdown()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::api::BluetoothServiceData;
+
+    fn advert(rssi: i32, frame: u8) -> BluetoothLEAdvertisementResponse {
+        BluetoothLEAdvertisementResponse {
+            address: 0xAABBCCDDEE01,
+            rssi,
+            service_data: vec![BluetoothServiceData {
+                uuid: "0000feaa-0000-1000-8000-00805f9b34fb".to_string(),
+                data: vec![frame, 1, 2, 3],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn queued(outbound: &Outbound) -> Vec<BluetoothLEAdvertisementResponse> {
+        let queue = outbound.queue.lock().unwrap();
+        queue
+            .pending
+            .iter()
+            .map(|pending| match pending {
+                Pending::Advert(slot) => queue.adverts[slot].advert.clone(),
+                _ => panic!("expected only advertisements"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repeats_are_coalesced_and_rotating_frames_kept() {
+        let stats = Arc::new(OutboundStats::default());
+        let outbound = Outbound::new("127.0.0.1:6053".parse().unwrap(), stats.clone());
+        outbound.push_advert(&advert(-70, 0x00)).unwrap();
+        outbound.push_advert(&advert(-65, 0x00)).unwrap();
+        outbound.push_advert(&advert(-60, 0x10)).unwrap();
+        outbound.push_advert(&advert(-62, 0x10)).unwrap();
+        outbound.push_advert(&advert(-64, 0x00)).unwrap();
+
+        let queued = queued(&outbound);
+        assert_eq!(
+            queued.iter().map(|a| a.rssi).collect::<Vec<_>>(),
+            [-65, -62, -64]
+        );
+        assert_eq!(
+            queued
+                .iter()
+                .map(|a| a.service_data[0].data[0])
+                .collect::<Vec<_>>(),
+            [0x00, 0x10, 0x00]
+        );
+        assert_eq!(stats.adverts_coalesced.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.adverts_dropped.load(Ordering::Relaxed), 0);
+    }
+}
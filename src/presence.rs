@@ -0,0 +1,107 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, Duration, Instant};
+
+use crate::api::api::{
+    BluetoothLEAdvertisementResponse, HomeassistantServiceMap, HomeassistantServiceResponse,
+};
+use crate::context::ProxyContext;
+use crate::utils::{address_to_mac, format_mac};
+
+/// Events fired for watched devices. Home Assistant accepts `esphome.`
+/// events from any device, without the device being allowed to call actions.
+pub const EVENT_DEVICE_SEEN: &str = "esphome.linux_bt_proxy_device_seen";
+pub const EVENT_DEVICE_LOST: &str = "esphome.linux_bt_proxy_device_lost";
+
+// How often present devices are checked for having gone quiet
+const LOST_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Present {
+    /// When this stretch of presence started
+    since: Instant,
+    name: String,
+}
+
+/// Tells subscribed clients (through `ProxyContext::presence_events`) when
+/// a watched device is heard after being away, and when it hasn't been
+/// heard for `ProxyContext::watch_timeout`.
+pub async fn run_presence(
+    ctx: Arc<ProxyContext>,
+    mut rx: broadcast::Receiver<BluetoothLEAdvertisementResponse>,
+) {
+    let mut present: HashMap<u64, Present> = HashMap::new();
+    let mut check = interval(LOST_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            advert = rx.recv() => match advert {
+                Ok(advert) if ctx.watched.contains(&advert.address) => {
+                    let name = String::from_utf8_lossy(&advert.name).into_owned();
+                    match present.get_mut(&advert.address) {
+                        Some(device) => {
+                            if device.name.is_empty() {
+                                device.name = name;
+                            }
+                        }
+                        None => {
+                            info!("Watched device {} seen (RSSI {})", format_mac(&address_to_mac(advert.address), ":"), advert.rssi);
+                            let mut event = event(&ctx, EVENT_DEVICE_SEEN, advert.address, &name);
+                            event.data.push(entry("rssi", advert.rssi.to_string()));
+                            let _ = ctx.presence_events.send(event);
+                            present.insert(advert.address, Present { since: Instant::now(), name });
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => warn!("Presence watcher missed {n} advertisements"),
+                Err(RecvError::Closed) => break,
+            },
+            _ = check.tick() => {
+                present.retain(|&address, device| {
+                    // The tracker may not have caught up with the advertisement
+                    // that started this stretch yet
+                    let last_heard = ctx
+                        .tracker
+                        .last_seen(address)
+                        .map_or(device.since, |seen| seen.max(device.since));
+                    if last_heard.elapsed() < ctx.watch_timeout {
+                        return true;
+                    }
+                    info!("Watched device {} lost", format_mac(&address_to_mac(address), ":"));
+                    let _ = ctx.presence_events.send(event(&ctx, EVENT_DEVICE_LOST, address, &device.name));
+                    false
+                });
+            },
+        }
+    }
+}
+
+fn entry(key: &str, value: String) -> HomeassistantServiceMap {
+    HomeassistantServiceMap {
+        key: key.to_string(),
+        value,
+        ..Default::default()
+    }
+}
+
+fn event(
+    ctx: &ProxyContext,
+    event: &str,
+    address: u64,
+    name: &str,
+) -> HomeassistantServiceResponse {
+    let mut data = vec![
+        entry("address", format_mac(&address_to_mac(address), ":")),
+        entry("proxy", ctx.hostname.clone()),
+    ];
+    if !name.is_empty() {
+        data.push(entry("name", name.to_string()));
+    }
+    HomeassistantServiceResponse {
+        service: event.to_string(),
+        data,
+        is_event: true,
+        ..Default::default()
+    }
+}
@@ -1,8 +1,21 @@
 use crate::api::api_options;
 use bytes::{Bytes, BytesMut};
-use protobuf::MessageFull;
+use protobuf::{Message, MessageFull};
+use std::fmt;
 use std::io::{Error, ErrorKind};
 
+/// First byte of every plaintext frame
+pub const PLAINTEXT_PREAMBLE: u8 = 0x00;
+/// First byte of every Noise frame, handshake and transport alike
+pub const NOISE_PREAMBLE: u8 = 0x01;
+
+/// Largest frame payload we accept. Noise frames can't be bigger anyway, and
+/// nothing Home Assistant sends comes close.
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
+// A u64 never needs more than 10 varint bytes
+const MAX_VARINT_LEN: usize = 10;
+
 pub fn get_message_id<M: MessageFull>() -> u8 {
     protobuf::ext::ExtFieldOptional::get(
         &api_options::exts::id,
@@ -12,19 +25,22 @@ pub fn get_message_id<M: MessageFull>() -> u8 {
 }
 
 /// Decodes a protobuf varint from a byte slice, returning the value and the number of bytes consumed.
+/// Fails with `UnexpectedEof` if the slice ends mid-varint, `InvalidData` if it can't be a u64.
 pub fn decode_varint(buf: &[u8]) -> Result<(u64, usize), Error> {
     let mut result = 0u64;
-    let mut shift = 0;
-    for (i, &byte) in buf.iter().enumerate() {
+    for (i, &byte) in buf.iter().take(MAX_VARINT_LEN).enumerate() {
         let val = (byte & 0x7F) as u64;
-        result |= val << shift;
+        // The tenth byte only has room for the top bit of a u64
+        if i == MAX_VARINT_LEN - 1 && val > 1 {
+            return Err(Error::new(ErrorKind::InvalidData, "Varint overflows u64"));
+        }
+        result |= val << (7 * i);
         if byte & 0x80 == 0 {
             return Ok((result, i + 1));
         }
-        shift += 7;
-        if shift > 63 {
-            return Err(Error::new(ErrorKind::InvalidData, "Varint too long"));
-        }
+    }
+    if buf.len() >= MAX_VARINT_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "Varint too long"));
     }
     Err(Error::new(ErrorKind::UnexpectedEof, "Incomplete varint"))
 }
@@ -45,30 +61,296 @@ pub fn encode_varint(mut value: u64) -> Vec<u8> {
     buf
 }
 
-pub fn next_message(buf: &mut BytesMut) -> Option<(u32, Bytes)> {
-    // Step 1: check framing byte
-    if buf.is_empty() || buf[0] != 0x00 {
-        return None;
+/// Builds a plaintext frame: preamble, payload length, message type, payload.
+pub fn encode_plaintext_frame(msg_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + 5 + 5 + payload.len()); // start + varints + payload
+    out.push(PLAINTEXT_PREAMBLE);
+    out.extend_from_slice(&encode_varint(payload.len() as u64));
+    out.extend_from_slice(&encode_varint(msg_type as u64));
+    out.extend_from_slice(payload);
+    out
+}
+
+pub fn encode_response<M: Message>(msg_type: u32, message: &M) -> Result<Vec<u8>, Error> {
+    Ok(encode_plaintext_frame(msg_type, &message.write_to_bytes()?))
+}
+
+/// Wraps a Noise handshake or transport message in a frame: preamble, then a
+/// big-endian u16 length.
+pub fn encode_noise_frame(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(3 + payload.len());
+    out.push(NOISE_PREAMBLE);
+    out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// A complete frame split off the receive buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Plaintext {
+        msg_type: u32,
+        payload: Bytes,
+    },
+    /// Still encrypted (or a handshake message); the Noise layer unpacks it
+    Noise(Bytes),
+}
+
+/// Why the receive buffer can't hold a valid frame. None of these can be
+/// recovered from: there's no way to find the next frame boundary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    BadPreamble(u8),
+    BadVarint,
+    /// Message type doesn't fit the protocol's id space
+    BadMessageType(u64),
+    Oversized(usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::BadPreamble(b) => write!(f, "bad frame preamble 0x{b:02x}"),
+            FrameError::BadVarint => write!(f, "malformed varint in frame header"),
+            FrameError::BadMessageType(t) => write!(f, "invalid message type {t}"),
+            FrameError::Oversized(n) => {
+                write!(f, "frame of {n} bytes exceeds limit of {MAX_MESSAGE_SIZE}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for Error {
+    fn from(e: FrameError) -> Self {
+        Error::new(ErrorKind::InvalidData, e)
     }
+}
 
+/// Splits the next frame off the front of `buf`. Returns `Ok(None)` when more
+/// bytes are needed, leaving the buffer untouched; errors leave it untouched
+/// too, and the connection should be dropped. Oversized frames are rejected
+/// as soon as their header arrives, so the buffer never grows past the limit.
+pub fn decode_frame(buf: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
+    match buf.first() {
+        None => Ok(None),
+        Some(&PLAINTEXT_PREAMBLE) => decode_plaintext_frame(buf),
+        Some(&NOISE_PREAMBLE) => decode_noise_frame(buf),
+        Some(&other) => Err(FrameError::BadPreamble(other)),
+    }
+}
+
+fn header_varint(buf: &[u8]) -> Result<Option<(u64, usize)>, FrameError> {
+    match decode_varint(buf) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(_) => Err(FrameError::BadVarint),
+    }
+}
+
+fn decode_plaintext_frame(buf: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
     let mut offset = 1;
 
-    // Step 2: parse total message length
-    let (length, len_size) = decode_varint(&buf[offset..]).ok()?;
+    // Payload length; it doesn't include the message type varint
+    let Some((length, len_size)) = header_varint(&buf[offset..])? else {
+        return Ok(None);
+    };
+    if length > MAX_MESSAGE_SIZE as u64 {
+        return Err(FrameError::Oversized(length as usize));
+    }
     offset += len_size;
 
-    // Step 3: make sure buffer contains entire message
-    if buf.len() < offset + length as usize {
-        return None;
+    let Some((msg_type, type_size)) = header_varint(&buf[offset..])? else {
+        return Ok(None);
+    };
+    if msg_type > u16::MAX as u64 {
+        return Err(FrameError::BadMessageType(msg_type));
     }
-
-    // Step 4: parse message type
-    let (msg_type, type_size) = decode_varint(&buf[offset..]).ok()?;
     offset += type_size;
 
-    let payload_len = length as usize;
-    let mut head = buf.split_to(offset + payload_len); // remove the message from buf
+    let length = length as usize;
+    if buf.len() < offset + length {
+        return Ok(None);
+    }
+
+    let mut head = buf.split_to(offset + length); // remove the message from buf
     let payload = head.split_off(offset).freeze(); // skip to payload and freeze it
+    Ok(Some(Frame::Plaintext {
+        msg_type: msg_type as u32,
+        payload,
+    }))
+}
+
+fn decode_noise_frame(buf: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
+    if buf.len() < 3 {
+        return Ok(None);
+    }
+    let length = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+    if buf.len() < 3 + length {
+        return Ok(None);
+    }
+    let mut head = buf.split_to(3 + length);
+    Ok(Some(Frame::Noise(head.split_off(3).freeze())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn decode_all(bytes: &[u8]) -> (Vec<Frame>, Result<(), FrameError>, usize) {
+        let mut buf = BytesMut::from(bytes);
+        let mut frames = Vec::new();
+        loop {
+            match decode_frame(&mut buf) {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => return (frames, Ok(()), buf.len()),
+                Err(e) => return (frames, Err(e), buf.len()),
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn varint_round_trips(value: u64) {
+            let encoded = encode_varint(value);
+            prop_assert!(encoded.len() <= MAX_VARINT_LEN);
+            prop_assert_eq!(decode_varint(&encoded).unwrap(), (value, encoded.len()));
+        }
+
+        #[test]
+        fn varint_ignores_trailing_bytes(value: u64, tail in prop::collection::vec(any::<u8>(), 0..8)) {
+            let mut encoded = encode_varint(value);
+            let len = encoded.len();
+            encoded.extend(tail);
+            prop_assert_eq!(decode_varint(&encoded).unwrap(), (value, len));
+        }
+
+        #[test]
+        fn truncated_varint_is_incomplete(value in 128u64.., cut in 1usize..10) {
+            let encoded = encode_varint(value);
+            let cut = cut.min(encoded.len() - 1);
+            let err = decode_varint(&encoded[..cut]).unwrap_err();
+            prop_assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        }
+
+        #[test]
+        fn decode_varint_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..16)) {
+            if let Ok((_, used)) = decode_varint(&bytes) {
+                prop_assert!(used <= bytes.len() && used <= MAX_VARINT_LEN);
+            }
+        }
+
+        #[test]
+        fn plaintext_frame_round_trips(msg_type in 0u32..=u16::MAX as u32,
+                                       payload in prop::collection::vec(any::<u8>(), 0..2048)) {
+            let encoded = encode_plaintext_frame(msg_type, &payload);
+            let (frames, result, left) = decode_all(&encoded);
+            prop_assert_eq!(result, Ok(()));
+            prop_assert_eq!(left, 0);
+            prop_assert_eq!(frames, vec![Frame::Plaintext { msg_type, payload: Bytes::from(payload) }]);
+        }
 
-    Some((msg_type as u32, payload))
+        #[test]
+        fn noise_frame_round_trips(payload in prop::collection::vec(any::<u8>(), 0..2048)) {
+            let encoded = encode_noise_frame(&payload);
+            let (frames, result, left) = decode_all(&encoded);
+            prop_assert_eq!(result, Ok(()));
+            prop_assert_eq!(left, 0);
+            prop_assert_eq!(frames, vec![Frame::Noise(Bytes::from(payload))]);
+        }
+
+        #[test]
+        fn frames_survive_arbitrary_chunking(
+            messages in prop::collection::vec((0u32..200, prop::collection::vec(any::<u8>(), 0..300)), 1..8),
+            chunk in 1usize..64,
+        ) {
+            let stream: Vec<u8> = messages
+                .iter()
+                .flat_map(|(t, p)| encode_plaintext_frame(*t, p))
+                .collect();
+
+            // Feed the stream in pieces, as the socket would
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            for piece in stream.chunks(chunk) {
+                buf.extend_from_slice(piece);
+                while let Some(frame) = decode_frame(&mut buf).unwrap() {
+                    decoded.push(frame);
+                }
+            }
+            prop_assert!(buf.is_empty());
+            let expected: Vec<Frame> = messages
+                .into_iter()
+                .map(|(msg_type, p)| Frame::Plaintext { msg_type, payload: Bytes::from(p) })
+                .collect();
+            prop_assert_eq!(decoded, expected);
+        }
+
+        #[test]
+        fn arbitrary_bytes_never_panic(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            let (frames, result, left) = decode_all(&bytes);
+            let payloads: usize = frames
+                .iter()
+                .map(|f| match f {
+                    Frame::Plaintext { payload, .. } | Frame::Noise(payload) => payload.len(),
+                })
+                .sum();
+            prop_assert!(payloads + left <= bytes.len());
+            if let Err(FrameError::BadPreamble(b)) = result {
+                prop_assert!(b != PLAINTEXT_PREAMBLE && b != NOISE_PREAMBLE);
+            }
+        }
+
+        #[test]
+        fn oversized_header_is_rejected_early(length in (MAX_MESSAGE_SIZE as u64 + 1)..u32::MAX as u64) {
+            let mut header = vec![PLAINTEXT_PREAMBLE];
+            header.extend(encode_varint(length));
+            let mut buf = BytesMut::from(&header[..]);
+            prop_assert_eq!(decode_frame(&mut buf), Err(FrameError::Oversized(length as usize)));
+        }
+    }
+
+    #[test]
+    fn varint_overflow_is_invalid() {
+        let mut bytes = vec![0xff; 9];
+        bytes.push(0x02);
+        assert_eq!(
+            decode_varint(&bytes).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        let endless = vec![0x80; 12];
+        assert_eq!(
+            decode_varint(&endless).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn bad_preamble_is_reported() {
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\n"[..]);
+        assert_eq!(decode_frame(&mut buf), Err(FrameError::BadPreamble(b'G')));
+        assert_eq!(buf.len(), 16);
+    }
+
+    #[test]
+    fn payload_split_from_type_is_incomplete() {
+        // Header complete, payload one byte short: the old decoder panicked here
+        let frame = encode_plaintext_frame(0x42, &[1, 2, 3, 4, 5]);
+        let mut buf = BytesMut::from(&frame[..frame.len() - 1]);
+        assert_eq!(decode_frame(&mut buf), Ok(None));
+        assert_eq!(buf.len(), frame.len() - 1);
+    }
+
+    #[test]
+    fn message_type_beyond_u16_is_invalid() {
+        let mut bytes = vec![PLAINTEXT_PREAMBLE, 0];
+        bytes.extend(encode_varint(0x1_0000));
+        let mut buf = BytesMut::from(&bytes[..]);
+        assert_eq!(
+            decode_frame(&mut buf),
+            Err(FrameError::BadMessageType(0x1_0000))
+        );
+    }
 }
@@ -0,0 +1,671 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast::Sender;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::api::api::{BluetoothLEAdvertisementResponse, BluetoothServiceData};
+use crate::utils::{mac_to_address, parse_mac};
+
+// btsnoop datalink types we know how to read
+const BTSNOOP_MAGIC: &[u8; 8] = b"btsnoop\0";
+const BTSNOOP_HCI_UNENCAPSULATED: u32 = 1001;
+const BTSNOOP_HCI_UART: u32 = 1002;
+
+// HCI constants for the advertising reports we replay
+const HCI_EVENT_PKT: u8 = 0x04;
+const HCI_EV_LE_META: u8 = 0x3e;
+const HCI_EV_LE_ADVERTISING_REPORT: u8 = 0x02;
+const HCI_EV_LE_EXT_ADV_REPORT: u8 = 0x0d;
+
+/// One advertisement from a capture, with its offset from the start of the capture.
+struct CapturedAdvertisement {
+    offset: Duration,
+    advert: BluetoothLEAdvertisementResponse,
+}
+
+/// A line of our own JSONL capture format. Field names follow the BlueZ
+/// Device1 properties the live listener reads, so captures can be written by
+/// hand from `busctl` or `bluetoothctl` output.
+///
+/// ```json
+/// {"ts": 12.5, "address": "AA:BB:CC:DD:EE:FF", "address_type": "random", "rssi": -70,
+///  "name": "Thermometer", "uuids": ["0000181a-0000-1000-8000-00805f9b34fb"],
+///  "service_data": {"0000181a-0000-1000-8000-00805f9b34fb": "a1b2c3"},
+///  "manufacturer_data": {"76": "0215..."}}
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonAdvertisement {
+    /// Seconds, on any epoch; only differences between lines matter.
+    ts: Option<f64>,
+    address: String,
+    #[serde(default)]
+    address_type: Option<String>,
+    #[serde(default = "default_rssi")]
+    rssi: i32,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    uuids: Vec<String>,
+    /// UUID -> hex encoded payload
+    #[serde(default)]
+    service_data: BTreeMap<String, String>,
+    /// Company id (decimal) -> hex encoded payload
+    #[serde(default)]
+    manufacturer_data: BTreeMap<String, String>,
+}
+
+fn default_rssi() -> i32 {
+    -127
+}
+
+/// Parses `--replay-speed`: 0 for as fast as possible, otherwise a factor
+/// small and large enough to keep the scaled offsets meaningful.
+pub fn parse_replay_speed(s: &str) -> Result<f64, String> {
+    let speed: f64 = s
+        .parse()
+        .map_err(|_| format!("Invalid replay speed: '{s}'"))?;
+    if speed == 0.0 || (0.001..=1000.0).contains(&speed) {
+        Ok(speed)
+    } else {
+        Err(format!(
+            "Replay speed must be 0 or between 0.001 and 1000, got '{s}'"
+        ))
+    }
+}
+
+/// Replays a capture file into the advertisement channel, standing in for a
+/// real adapter. `speed` scales the recorded timing (2.0 is twice as fast);
+/// a speed of 0 sends everything as fast as the channel accepts it.
+pub async fn run_replay_source(
+    path: PathBuf,
+    speed: f64,
+    repeat: bool,
+    tx: Sender<BluetoothLEAdvertisementResponse>,
+) -> Result<()> {
+    let captured = load_capture(&path)?;
+    if captured.is_empty() {
+        bail!("No advertisements found in {}", path.display());
+    }
+    info!(
+        "Replaying {} advertisements from {} (speed {speed}, loop {repeat})",
+        captured.len(),
+        path.display()
+    );
+
+    loop {
+        let start = Instant::now();
+        for captured in &captured {
+            if speed > 0.0 {
+                sleep_until(start + captured.offset.div_f64(speed)).await;
+            }
+            if let Err(e) = tx.send(captured.advert.clone()) {
+                debug!("No receivers for replayed advertisement: {e}");
+            }
+            if speed <= 0.0 {
+                // Don't starve the rest of the runtime when replaying flat out
+                tokio::task::yield_now().await;
+            }
+        }
+        if !repeat {
+            break;
+        }
+        debug!("Capture finished, looping");
+    }
+
+    info!("Replay of {} finished", path.display());
+    // Keep the source alive like a real adapter that has gone quiet
+    std::future::pending::<()>().await;
+    Ok(())
+}
+
+fn load_capture(path: &Path) -> Result<Vec<CapturedAdvertisement>> {
+    let data = std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+    if data.starts_with(BTSNOOP_MAGIC) {
+        parse_btsnoop(&data)
+    } else {
+        parse_jsonl(&data)
+    }
+}
+
+fn parse_jsonl(data: &[u8]) -> Result<Vec<CapturedAdvertisement>> {
+    let text = std::str::from_utf8(data).context("Capture is neither btsnoop nor UTF-8 JSONL")?;
+    let mut captured = Vec::new();
+    let mut first_ts = None;
+    let mut last_offset = Duration::ZERO;
+
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry: JsonAdvertisement = serde_json::from_str(line)
+            .with_context(|| format!("Invalid capture entry on line {}", lineno + 1))?;
+
+        // Lines without a timestamp are sent together with the previous one
+        let offset = match entry.ts {
+            Some(ts) => {
+                let first = *first_ts.get_or_insert(ts);
+                Duration::try_from_secs_f64(ts - first).unwrap_or(last_offset)
+            }
+            None => last_offset,
+        };
+        last_offset = offset;
+
+        let advert = json_to_advertisement(entry)
+            .with_context(|| format!("Invalid capture entry on line {}", lineno + 1))?;
+        captured.push(CapturedAdvertisement { offset, advert });
+    }
+    Ok(captured)
+}
+
+fn json_to_advertisement(entry: JsonAdvertisement) -> Result<BluetoothLEAdvertisementResponse> {
+    let service_data = entry
+        .service_data
+        .into_iter()
+        .map(|(uuid, data)| {
+            Ok(BluetoothServiceData {
+                uuid,
+                data: hex::decode(data)?,
+                ..Default::default()
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let manufacturer_data = entry
+        .manufacturer_data
+        .into_iter()
+        .map(|(uuid, data)| {
+            Ok(BluetoothServiceData {
+                uuid,
+                data: hex::decode(data)?,
+                ..Default::default()
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mac = parse_mac(&entry.address)
+        .map_err(|e| anyhow!("Invalid address '{}': {e}", entry.address))?;
+    let address_type = match entry.address_type.as_deref() {
+        None | Some("public") => 0,
+        Some("random") => 1,
+        Some(other) => bail!("Invalid address type '{other}' (expected public or random)"),
+    };
+
+    Ok(BluetoothLEAdvertisementResponse {
+        address: mac_to_address(mac),
+        address_type,
+        name: entry.name.map_or_else(Vec::new, String::into_bytes),
+        rssi: entry.rssi,
+        service_uuids: entry.uuids,
+        service_data,
+        manufacturer_data,
+        ..Default::default()
+    })
+}
+
+fn parse_btsnoop(data: &[u8]) -> Result<Vec<CapturedAdvertisement>> {
+    if data.len() < 16 {
+        bail!("Truncated btsnoop header");
+    }
+    let version = u32::from_be_bytes(data[8..12].try_into().unwrap());
+    let datalink = u32::from_be_bytes(data[12..16].try_into().unwrap());
+    if version != 1 {
+        bail!("Unsupported btsnoop version {version}");
+    }
+    if datalink != BTSNOOP_HCI_UNENCAPSULATED && datalink != BTSNOOP_HCI_UART {
+        bail!("Unsupported btsnoop datalink type {datalink}");
+    }
+
+    let mut captured = Vec::new();
+    let mut first_ts = None;
+    let mut pos = 16;
+
+    while pos + 24 <= data.len() {
+        let incl_len = u32::from_be_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let flags = u32::from_be_bytes(data[pos + 8..pos + 12].try_into().unwrap());
+        let ts = i64::from_be_bytes(data[pos + 16..pos + 24].try_into().unwrap());
+        pos += 24;
+        if pos + incl_len > data.len() {
+            warn!("Truncated btsnoop record at end of capture");
+            break;
+        }
+        let record = &data[pos..pos + incl_len];
+        pos += incl_len;
+
+        // Unencapsulated captures mark events in the flags, H4 ones carry the packet type
+        let event = match datalink {
+            BTSNOOP_HCI_UART => match record.split_first() {
+                Some((&HCI_EVENT_PKT, rest)) => rest,
+                _ => continue,
+            },
+            _ if flags & 0x03 == 0x03 => record,
+            _ => continue,
+        };
+
+        // Timestamps are microseconds; we only need them relative to the first record
+        let first = *first_ts.get_or_insert(ts);
+        let offset = Duration::from_micros(ts.saturating_sub(first).max(0) as u64);
+
+        for advert in parse_le_advertising_event(event) {
+            captured.push(CapturedAdvertisement { offset, advert });
+        }
+    }
+    Ok(captured)
+}
+
+/// Pulls advertisements out of an HCI LE Meta event. Anything else yields nothing.
+fn parse_le_advertising_event(event: &[u8]) -> Vec<BluetoothLEAdvertisementResponse> {
+    let mut adverts = Vec::new();
+    if event.len() < 4 || event[0] != HCI_EV_LE_META {
+        return adverts;
+    }
+    let params = &event[2..];
+    let subevent = params[0];
+    let num_reports = params[1];
+    let mut p = &params[2..];
+
+    for _ in 0..num_reports {
+        let parsed = match subevent {
+            HCI_EV_LE_ADVERTISING_REPORT => parse_legacy_report(p),
+            HCI_EV_LE_EXT_ADV_REPORT => parse_extended_report(p),
+            _ => None,
+        };
+        let Some((advert, consumed)) = parsed else {
+            break;
+        };
+        adverts.push(advert);
+        p = &p[consumed..];
+    }
+    adverts
+}
+
+fn parse_legacy_report(p: &[u8]) -> Option<(BluetoothLEAdvertisementResponse, usize)> {
+    // evt_type(1) addr_type(1) addr(6) len(1) data(len) rssi(1)
+    let data_len = *p.get(8)? as usize;
+    let end = 9 + data_len;
+    let rssi = *p.get(end)? as i8;
+    let advert = build_advertisement(p[1], &p[2..8], &p[9..end], rssi);
+    Some((advert, end + 1))
+}
+
+fn parse_extended_report(p: &[u8]) -> Option<(BluetoothLEAdvertisementResponse, usize)> {
+    // evt_type(2) addr_type(1) addr(6) phys(2) sid(1) tx_power(1) rssi(1)
+    // interval(2) direct_addr_type(1) direct_addr(6) len(1) data(len)
+    let data_len = *p.get(23)? as usize;
+    let end = 24 + data_len;
+    let ad = p.get(24..end)?;
+    let advert = build_advertisement(p[2], &p[3..9], ad, p[13] as i8);
+    Some((advert, end))
+}
+
+fn build_advertisement(
+    addr_type: u8,
+    addr: &[u8],
+    ad: &[u8],
+    rssi: i8,
+) -> BluetoothLEAdvertisementResponse {
+    // HCI addresses are little-endian
+    let mut mac: [u8; 6] = addr.try_into().unwrap();
+    mac.reverse();
+    let mut advert = BluetoothLEAdvertisementResponse {
+        address: mac_to_address(mac),
+        address_type: (addr_type & 0x01) as u32,
+        rssi: rssi as i32,
+        ..Default::default()
+    };
+
+    let mut rest = ad;
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = len as usize;
+        if len == 0 || len > tail.len() {
+            break;
+        }
+        let (ad_type, value) = (tail[0], &tail[1..len]);
+        rest = &tail[len..];
+
+        match ad_type {
+            0x08 | 0x09 => advert.name = value.to_vec(),
+            0x02 | 0x03 => advert
+                .service_uuids
+                .extend(value.chunks_exact(2).map(uuid_from_le)),
+            0x04 | 0x05 => advert
+                .service_uuids
+                .extend(value.chunks_exact(4).map(uuid_from_le)),
+            0x06 | 0x07 => advert
+                .service_uuids
+                .extend(value.chunks_exact(16).map(uuid_from_le)),
+            0x16 | 0x20 | 0x21 => {
+                let uuid_len = match ad_type {
+                    0x16 => 2,
+                    0x20 => 4,
+                    _ => 16,
+                };
+                if value.len() >= uuid_len {
+                    advert.service_data.push(BluetoothServiceData {
+                        uuid: uuid_from_le(&value[..uuid_len]),
+                        data: value[uuid_len..].to_vec(),
+                        ..Default::default()
+                    });
+                }
+            }
+            0xff if value.len() >= 2 => {
+                advert.manufacturer_data.push(BluetoothServiceData {
+                    uuid: u16::from_le_bytes([value[0], value[1]]).to_string(),
+                    data: value[2..].to_vec(),
+                    ..Default::default()
+                });
+            }
+            _ => {}
+        }
+    }
+    advert
+}
+
+/// Formats a little-endian 16, 32 or 128 bit UUID the way BlueZ reports it.
+fn uuid_from_le(bytes: &[u8]) -> String {
+    let be: Vec<u8> = bytes.iter().rev().copied().collect();
+    match be.len() {
+        2 => format!("0000{}-0000-1000-8000-00805f9b34fb", hex::encode(&be)),
+        4 => format!("{}-0000-1000-8000-00805f9b34fb", hex::encode(&be)),
+        _ => {
+            let h = hex::encode(&be);
+            format!(
+                "{}-{}-{}-{}-{}",
+                &h[0..8],
+                &h[8..12],
+                &h[12..16],
+                &h[16..20],
+                &h[20..32]
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn btsnoop(datalink: u32, records: &[(u32, i64, &[u8])]) -> Vec<u8> {
+        let mut data = BTSNOOP_MAGIC.to_vec();
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(&datalink.to_be_bytes());
+        for &(flags, ts, record) in records {
+            let len = (record.len() as u32).to_be_bytes();
+            data.extend_from_slice(&len);
+            data.extend_from_slice(&len);
+            data.extend_from_slice(&flags.to_be_bytes());
+            data.extend_from_slice(&0u32.to_be_bytes());
+            data.extend_from_slice(&ts.to_be_bytes());
+            data.extend_from_slice(record);
+        }
+        data
+    }
+
+    // Flags, name "Tag" and a 16-bit service data entry
+    const AD: &[u8] = &[
+        0x02, 0x01, 0x06, 0x04, 0x09, b'T', b'a', b'g', 0x05, 0x16, 0x1a, 0x18, 0xa1, 0xb2,
+    ];
+    const ADDR: [u8; 6] = [0x66, 0x55, 0x44, 0x33, 0x22, 0x11];
+
+    fn legacy_event(reports: &[(&[u8], i8)]) -> Vec<u8> {
+        let mut params = vec![HCI_EV_LE_ADVERTISING_REPORT, reports.len() as u8];
+        for &(ad, rssi) in reports {
+            params.extend_from_slice(&[0x00, 0x01]);
+            params.extend_from_slice(&ADDR);
+            params.push(ad.len() as u8);
+            params.extend_from_slice(ad);
+            params.push(rssi as u8);
+        }
+        let mut event = vec![HCI_EV_LE_META, params.len() as u8];
+        event.extend(params);
+        event
+    }
+
+    fn extended_event(ad: &[u8], rssi: i8) -> Vec<u8> {
+        let mut params = vec![HCI_EV_LE_EXT_ADV_REPORT, 1, 0x13, 0x00, 0x00];
+        params.extend_from_slice(&ADDR);
+        params.extend_from_slice(&[0x01, 0x00, 0xff, 0x7f, rssi as u8, 0x00, 0x00, 0x00]);
+        params.extend_from_slice(&[0; 6]);
+        params.push(ad.len() as u8);
+        params.extend_from_slice(ad);
+        let mut event = vec![HCI_EV_LE_META, params.len() as u8];
+        event.extend(params);
+        event
+    }
+
+    fn uart(event: &[u8]) -> Vec<u8> {
+        let mut record = vec![HCI_EVENT_PKT];
+        record.extend_from_slice(event);
+        record
+    }
+
+    #[test]
+    fn parses_replay_speeds() {
+        assert_eq!(parse_replay_speed("0"), Ok(0.0));
+        assert_eq!(parse_replay_speed("2.5"), Ok(2.5));
+        assert_eq!(parse_replay_speed("0.001"), Ok(0.001));
+        assert_eq!(parse_replay_speed("1000"), Ok(1000.0));
+        for bad in ["NaN", "inf", "-1", "1e-300", "0.0009", "1001", "fast"] {
+            assert!(parse_replay_speed(bad).is_err(), "{bad}");
+        }
+    }
+
+    fn jsonl_error(text: &str) -> String {
+        format!("{:#}", parse_jsonl(text.as_bytes()).err().unwrap())
+    }
+
+    #[test]
+    fn parses_jsonl_captures() {
+        let text = r#"
+# Hand-written capture
+{"ts": 100.0, "address": "AA:BB:CC:DD:EE:01", "address_type": "random", "rssi": -70, "name": "Thermometer", "uuids": ["0000181a-0000-1000-8000-00805f9b34fb"], "service_data": {"0000181a-0000-1000-8000-00805f9b34fb": "a1b2c3"}, "manufacturer_data": {"76": "0215"}}
+{"ts": 100.5, "address": "aa:bb:cc:dd:ee:02", "address_type": "public"}
+
+{"address": "AA:BB:CC:DD:EE:03"}
+{"ts": 102.0, "address": "AA:BB:CC:DD:EE:04"}
+"#;
+        let captured = parse_jsonl(text.as_bytes()).unwrap();
+
+        // Relative to the first line; a line without a timestamp goes with the one before
+        let offsets: Vec<_> = captured.iter().map(|c| c.offset).collect();
+        assert_eq!(
+            offsets,
+            [
+                Duration::ZERO,
+                Duration::from_millis(500),
+                Duration::from_millis(500),
+                Duration::from_secs(2)
+            ]
+        );
+        let advert = &captured[0].advert;
+        assert_eq!(advert.address, 0xAABBCCDDEE01);
+        assert_eq!(advert.address_type, 1);
+        assert_eq!(advert.rssi, -70);
+        assert_eq!(advert.name, b"Thermometer");
+        assert_eq!(
+            advert.service_uuids,
+            ["0000181a-0000-1000-8000-00805f9b34fb"]
+        );
+        assert_eq!(advert.service_data[0].data, [0xa1, 0xb2, 0xc3]);
+        assert_eq!(advert.manufacturer_data[0].uuid, "76");
+        assert_eq!(advert.manufacturer_data[0].data, [0x02, 0x15]);
+
+        let advert = &captured[1].advert;
+        assert_eq!(advert.address, 0xAABBCCDDEE02);
+        assert_eq!(advert.address_type, 0);
+        assert_eq!(advert.rssi, -127);
+        assert!(advert.name.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_jsonl_entries() {
+        let good = r#"{"address": "AA:BB:CC:DD:EE:01"}"#;
+        for (line, error) in [
+            (
+                r#"{"address": "AA:BB:CC:DD:EE:02", "service_data": {"180a": "a1b"}}"#,
+                "Odd number of digits",
+            ),
+            (
+                r#"{"address": "AA:BB:CC:DD:EE:02", "manufacturer_data": {"76": "zz"}}"#,
+                "Invalid character",
+            ),
+            (
+                r#"{"address": "AA:BB:CC:DD:EE"}"#,
+                "Invalid address 'AA:BB:CC:DD:EE'",
+            ),
+            (
+                r#"{"address": "AA:BB:CC:DD:EE:GG"}"#,
+                "Invalid hex byte: 'GG'",
+            ),
+            (
+                r#"{"address": "AA:BB:CC:DD:EE:02", "address_type": "static"}"#,
+                "Invalid address type 'static'",
+            ),
+            (
+                r#"{"address": "AA:BB:CC:DD:EE:02", "tx_power": 4}"#,
+                "unknown field `tx_power`",
+            ),
+        ] {
+            let e = jsonl_error(&format!("{good}\n\n{line}\n"));
+            assert!(e.starts_with("Invalid capture entry on line 3"), "{e}");
+            assert!(e.contains(error), "{e}");
+        }
+    }
+
+    #[test]
+    fn parses_legacy_reports_from_uart_captures() {
+        let first = uart(&legacy_event(&[(AD, -60)]));
+        let second = uart(&legacy_event(&[(AD, -61), (&[], -62)]));
+        let data = btsnoop(
+            BTSNOOP_HCI_UART,
+            &[(3, 1_000_000, &first), (3, 3_500_000, &second)],
+        );
+        let captured = parse_btsnoop(&data).unwrap();
+
+        assert_eq!(captured.len(), 3);
+        let offsets: Vec<_> = captured.iter().map(|c| c.offset).collect();
+        assert_eq!(
+            offsets,
+            [
+                Duration::ZERO,
+                Duration::from_millis(2500),
+                Duration::from_millis(2500)
+            ]
+        );
+        let advert = &captured[0].advert;
+        assert_eq!(advert.address, 0x112233445566);
+        assert_eq!(advert.address_type, 1);
+        assert_eq!(advert.rssi, -60);
+        assert_eq!(advert.name, b"Tag");
+        assert_eq!(
+            advert.service_data[0].uuid,
+            "0000181a-0000-1000-8000-00805f9b34fb"
+        );
+        assert_eq!(advert.service_data[0].data, [0xa1, 0xb2]);
+        assert!(captured[2].advert.name.is_empty());
+        assert_eq!(captured[2].advert.rssi, -62);
+    }
+
+    #[test]
+    fn parses_extended_reports_from_unencapsulated_captures() {
+        let event = extended_event(AD, -70);
+        // Commands and received ACL data are skipped
+        let data = btsnoop(
+            BTSNOOP_HCI_UNENCAPSULATED,
+            &[(2, 0, &[0x01, 0x0c, 0x00]), (1, 0, &event), (3, 10, &event)],
+        );
+        let captured = parse_btsnoop(&data).unwrap();
+
+        assert_eq!(captured.len(), 1);
+        let advert = &captured[0].advert;
+        assert_eq!(advert.address, 0x112233445566);
+        assert_eq!(advert.rssi, -70);
+        assert_eq!(advert.name, b"Tag");
+    }
+
+    #[test]
+    fn parses_advertising_data() {
+        let ad = [
+            0x03, 0x03, 0x0f, 0x18, // 16-bit UUID
+            0x05, 0x05, 0x78, 0x56, 0x34, 0x12, // 32-bit UUID
+            0x05, 0xff, 0x4c, 0x00, 0x02, 0x15, // manufacturer data
+            0x01, 0xff, // manufacturer data too short for a company id
+            0x09, 0x16, 0x01, // length running past the end
+        ];
+        let advert = build_advertisement(0, &ADDR, &ad, -50);
+
+        assert_eq!(advert.address_type, 0);
+        assert_eq!(
+            advert.service_uuids,
+            [
+                "0000180f-0000-1000-8000-00805f9b34fb",
+                "12345678-0000-1000-8000-00805f9b34fb"
+            ]
+        );
+        assert_eq!(advert.manufacturer_data.len(), 1);
+        assert_eq!(advert.manufacturer_data[0].uuid, "76");
+        assert_eq!(advert.manufacturer_data[0].data, [0x02, 0x15]);
+        assert!(advert.service_data.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut data = btsnoop(BTSNOOP_HCI_UART, &[]);
+        assert!(parse_btsnoop(&data[..15]).is_err());
+
+        data[11] = 2;
+        assert!(parse_btsnoop(&data).is_err());
+
+        let data = btsnoop(1003, &[]);
+        assert!(parse_btsnoop(&data).is_err());
+    }
+
+    #[test]
+    fn truncated_records_end_the_capture() {
+        let record = uart(&legacy_event(&[(AD, -60)]));
+        let data = btsnoop(BTSNOOP_HCI_UART, &[(3, 0, &record), (3, 1, &record)]);
+
+        // Cut into the second record's data, then into its header
+        for cut in [1, record.len() + 1] {
+            let captured = parse_btsnoop(&data[..data.len() - cut]).unwrap();
+            assert_eq!(captured.len(), 1);
+        }
+    }
+
+    #[test]
+    fn timestamps_far_apart_do_not_overflow() {
+        let record = uart(&legacy_event(&[(AD, -60)]));
+        let data = btsnoop(
+            BTSNOOP_HCI_UART,
+            &[(3, i64::MIN, &record), (3, i64::MAX, &record)],
+        );
+        let captured = parse_btsnoop(&data).unwrap();
+        assert_eq!(captured.len(), 2);
+    }
+
+    #[test]
+    fn malformed_reports_yield_what_parsed() {
+        // Claims two reports but carries one
+        let mut event = legacy_event(&[(AD, -60)]);
+        event[3] = 2;
+        assert_eq!(parse_le_advertising_event(&event).len(), 1);
+
+        // Data length running past the end of the event, at every cut
+        let event = legacy_event(&[(AD, -60)]);
+        for len in 0..event.len() {
+            assert!(parse_le_advertising_event(&event[..len]).is_empty());
+        }
+        let event = extended_event(AD, -60);
+        for len in 0..event.len() {
+            assert!(parse_le_advertising_event(&event[..len]).is_empty());
+        }
+
+        // Other events and subevents
+        assert!(parse_le_advertising_event(&[0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00]).is_empty());
+        assert!(parse_le_advertising_event(&[HCI_EV_LE_META, 0x02, 0x01, 0x00]).is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+use log::info;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::ble::ScanMode;
+
+/// Scanner settings a rule forces while it matches. Unset fields leave the
+/// settings chosen from Home Assistant alone.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScanOverrides {
+    pub enabled: Option<bool>,
+    pub mode: Option<ScanMode>,
+    /// Advertisements weaker than this (dBm) aren't forwarded
+    pub min_rssi: Option<i32>,
+}
+
+impl ScanOverrides {
+    /// `other`'s settings win where it has any.
+    fn merge(self, other: ScanOverrides) -> ScanOverrides {
+        ScanOverrides {
+            enabled: other.enabled.or(self.enabled),
+            mode: other.mode.or(self.mode),
+            min_rssi: other.min_rssi.or(self.min_rssi),
+        }
+    }
+}
+
+/// Applies scanner settings while a Home Assistant entity (or one of its
+/// attributes) has a given state, written
+/// `ENTITY[ATTRIBUTE]=STATE;SETTING=VALUE,...`, e.g.
+/// `media_player.living_room=playing;mode=passive`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rule {
+    pub entity_id: String,
+    /// Empty for the entity's state itself
+    pub attribute: String,
+    pub state: String,
+    pub overrides: ScanOverrides,
+    text: String,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (condition, settings) = text
+            .split_once(';')
+            .ok_or("expected ENTITY=STATE;SETTING=VALUE,...")?;
+        let (entity, state) = condition
+            .split_once('=')
+            .ok_or_else(|| format!("expected ENTITY=STATE, got '{condition}'"))?;
+        let (entity_id, attribute) = match entity.trim().split_once('[') {
+            Some((entity_id, attribute)) => (
+                entity_id,
+                attribute
+                    .strip_suffix(']')
+                    .ok_or_else(|| format!("unclosed attribute in '{entity}'"))?,
+            ),
+            None => (entity.trim(), ""),
+        };
+        if !entity_id.contains('.') {
+            return Err(format!(
+                "'{entity_id}' is not an entity id like domain.name"
+            ));
+        }
+
+        let mut overrides = ScanOverrides::default();
+        for setting in settings.split(',') {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected SETTING=VALUE, got '{setting}'"))?;
+            let value = value.trim();
+            match name.trim() {
+                "scanning" => {
+                    overrides.enabled = Some(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(format!("scanning must be on or off, not '{value}'")),
+                    })
+                }
+                "mode" => {
+                    overrides.mode = Some(match value.to_lowercase().as_str() {
+                        "active" => ScanMode::Active,
+                        "passive" => ScanMode::Passive,
+                        _ => return Err(format!("mode must be active or passive, not '{value}'")),
+                    })
+                }
+                "min_rssi" => {
+                    overrides.min_rssi = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("min_rssi must be a number, not '{value}'"))?,
+                    )
+                }
+                name => {
+                    return Err(format!(
+                        "unknown setting '{name}' (expected scanning, mode or min_rssi)"
+                    ))
+                }
+            }
+        }
+
+        Ok(Rule {
+            entity_id: entity_id.to_string(),
+            attribute: attribute.to_string(),
+            state: state.trim().to_string(),
+            overrides,
+            text: text.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// The configured rules, and the states Home Assistant last reported for
+/// the entities they watch.
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// By entity id and attribute
+    states: Mutex<HashMap<(String, String), String>>,
+    /// Connections subscribed to those states
+    feeds: AtomicUsize,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Rules {
+            rules,
+            states: Mutex::new(HashMap::new()),
+            feeds: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// What to ask Home Assistant for, each entity and attribute once.
+    pub fn subscriptions(&self) -> Vec<(&str, &str)> {
+        let mut subscriptions: Vec<(&str, &str)> = Vec::new();
+        for rule in &self.rules {
+            let subscription = (rule.entity_id.as_str(), rule.attribute.as_str());
+            if !subscriptions.contains(&subscription) {
+                subscriptions.push(subscription);
+            }
+        }
+        subscriptions
+    }
+
+    /// A connection subscribed to the states the rules watch.
+    pub fn add_feed(&self) {
+        self.feeds.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// That connection closed. Once none are left the recorded states are
+    /// stale, so they're forgotten; returns true then.
+    pub fn remove_feed(&self) -> bool {
+        if self.feeds.fetch_sub(1, Ordering::Relaxed) != 1 {
+            return false;
+        }
+        self.states.lock().unwrap().clear();
+        true
+    }
+
+    /// Records a state Home Assistant sent. Returns the combined overrides
+    /// of the rules that match now, later rules winning, or `None` if no
+    /// rule watches this entity.
+    pub fn update(&self, entity_id: &str, attribute: &str, state: &str) -> Option<ScanOverrides> {
+        let watched = |rule: &&Rule| rule.entity_id == entity_id && rule.attribute == attribute;
+        if !self.rules.iter().any(|rule| watched(&rule)) {
+            return None;
+        }
+        let mut states = self.states.lock().unwrap();
+        let key = (entity_id.to_string(), attribute.to_string());
+        let previous = states.insert(key, state.to_string());
+        for rule in self.rules.iter().filter(watched) {
+            let matched = previous.as_deref() == Some(rule.state.as_str());
+            match (matched, rule.state == state) {
+                (false, true) => info!("Rule '{rule}' applies"),
+                (true, false) => info!("Rule '{rule}' no longer applies"),
+                _ => {}
+            }
+        }
+        Some(
+            self.rules
+                .iter()
+                .filter(|rule| {
+                    states.get(&(rule.entity_id.clone(), rule.attribute.clone()))
+                        == Some(&rule.state)
+                })
+                .fold(ScanOverrides::default(), |overrides, rule| {
+                    overrides.merge(rule.overrides)
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(texts: &[&str]) -> Rules {
+        Rules::new(texts.iter().map(|text| text.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule: Rule = "media_player.living_room=playing;mode=passive"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.entity_id, "media_player.living_room");
+        assert_eq!(rule.attribute, "");
+        assert_eq!(rule.state, "playing");
+        assert_eq!(
+            rule.overrides,
+            ScanOverrides {
+                mode: Some(ScanMode::Passive),
+                ..Default::default()
+            }
+        );
+
+        let rule: Rule = " climate.hall[hvac_action] = heating ;scanning=off, min_rssi=-80"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.entity_id, "climate.hall");
+        assert_eq!(rule.attribute, "hvac_action");
+        assert_eq!(rule.state, "heating");
+        assert_eq!(
+            rule.overrides,
+            ScanOverrides {
+                enabled: Some(false),
+                mode: None,
+                min_rssi: Some(-80),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        for (text, error) in [
+            (
+                "sun.sun=below_horizon",
+                "expected ENTITY=STATE;SETTING=VALUE",
+            ),
+            ("sun.sun;mode=passive", "expected ENTITY=STATE"),
+            ("sun=up;mode=passive", "not an entity id"),
+            ("sun.sun[elevation=5;mode=passive", "unclosed attribute"),
+            ("sun.sun=up;mode", "expected SETTING=VALUE"),
+            ("sun.sun=up;scanning=maybe", "scanning must be on or off"),
+            ("sun.sun=up;mode=loud", "mode must be active or passive"),
+            ("sun.sun=up;min_rssi=weak", "min_rssi must be a number"),
+            ("sun.sun=up;volume=11", "unknown setting 'volume'"),
+        ] {
+            let result = text.parse::<Rule>();
+            assert!(
+                result.as_ref().is_err_and(|e| e.contains(error)),
+                "{text}: {:?}",
+                result.map(|rule| rule.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn unwatched_entities_change_nothing() {
+        let rules = rules(&["media_player.tv=playing;mode=passive"]);
+        assert_eq!(rules.update("media_player.radio", "", "playing"), None);
+        assert_eq!(rules.update("media_player.tv", "source", "playing"), None);
+    }
+
+    #[test]
+    fn matching_rules_apply_until_the_state_changes() {
+        let rules = rules(&["media_player.tv=playing;mode=passive"]);
+        let passive = ScanOverrides {
+            mode: Some(ScanMode::Passive),
+            ..Default::default()
+        };
+        assert_eq!(
+            rules.update("media_player.tv", "", "playing"),
+            Some(passive)
+        );
+        assert_eq!(
+            rules.update("media_player.tv", "", "idle"),
+            Some(ScanOverrides::default())
+        );
+    }
+
+    #[test]
+    fn attributes_are_matched_separately() {
+        let rules = rules(&[
+            "climate.hall=heat;scanning=off",
+            "climate.hall[hvac_action]=heating;min_rssi=-70",
+        ]);
+        assert_eq!(
+            rules.update("climate.hall", "hvac_action", "heating"),
+            Some(ScanOverrides {
+                min_rssi: Some(-70),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            rules.update("climate.hall", "", "heat"),
+            Some(ScanOverrides {
+                enabled: Some(false),
+                mode: None,
+                min_rssi: Some(-70),
+            })
+        );
+    }
+
+    #[test]
+    fn later_rules_win() {
+        let rules = rules(&[
+            "media_player.tv=playing;mode=passive,min_rssi=-60",
+            "binary_sensor.pairing=on;mode=active,scanning=on",
+        ]);
+        rules.update("media_player.tv", "", "playing");
+        assert_eq!(
+            rules.update("binary_sensor.pairing", "", "on"),
+            Some(ScanOverrides {
+                enabled: Some(true),
+                mode: Some(ScanMode::Active),
+                min_rssi: Some(-60),
+            })
+        );
+    }
+
+    #[test]
+    fn states_are_forgotten_with_the_last_feed() {
+        let rules = rules(&[
+            "media_player.tv=playing;mode=passive",
+            "binary_sensor.pairing=on;min_rssi=-60",
+        ]);
+        rules.add_feed();
+        rules.add_feed();
+        rules.update("media_player.tv", "", "playing");
+        assert!(!rules.remove_feed());
+        assert!(rules.remove_feed());
+        // The TV's state from before is gone
+        assert_eq!(
+            rules.update("binary_sensor.pairing", "", "on"),
+            Some(ScanOverrides {
+                min_rssi: Some(-60),
+                ..Default::default()
+            })
+        );
+    }
+}
@@ -1,69 +1,241 @@
-use bytes::BytesMut;
 use log::{debug, info, warn};
 use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, IntoRawFd};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::io::AsyncReadExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinSet;
+use tokio::time::{
+    interval, interval_at, sleep_until, timeout, Duration, Instant, MissedTickBehavior,
+};
 
-use crate::api::api::BluetoothLEAdvertisementResponse;
-use crate::context::ProxyContext;
+use crate::api::api::{
+    BluetoothLEAdvertisementResponse, HomeassistantServiceResponse, SubscribeLogsResponse,
+};
+use crate::connection::ApiConnection;
+use crate::context::{ClientInfo, ProxyContext};
+use crate::dispatch::{self, ClientMessage};
+use crate::entities::StateCache;
 use crate::handlers::{
-    connect_request, device_info_request, disconnect_request, forward_ble_advertisement,
-    hello_request, list_entities_request, ping_request,
-    subscribe_bluetooth_connections_free_request, subscribe_bluetooth_le_advertisements_request,
-    SubscriptionFlags,
+    bluetooth_scanner_set_mode_request, button_command_request, connect_request,
+    device_info_request, disconnect_request, execute_service_request, forward_ble_advertisement,
+    get_time_request, get_time_response, hello_request, home_assistant_state_response,
+    list_entities_request, noise_encryption_set_key_request, ping_request, select_command_request,
+    send_disconnect_request, send_get_time_request, send_ping_request, send_scanner_state,
+    send_state_updates, subscribe_bluetooth_connections_free_request,
+    subscribe_bluetooth_le_advertisements_request, subscribe_home_assistant_states_request,
+    subscribe_homeassistant_services_request, subscribe_logs_request, subscribe_states_request,
+    switch_command_request, SubscriptionFlags,
 };
-use crate::proto::next_message;
+use crate::logs::LogSubscription;
+use crate::outbound::Outbound;
+use crate::rules::ScanOverrides;
+use crate::session::{Session, SessionState, Verdict};
+
+// How long a client gets to answer our DisconnectRequest
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// The same when shutting down, kept short so stopping the service is quick
+const SHUTDOWN_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// How often subscribed clients get changed entity states
+const STATE_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub async fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening on {addr}");
+    Ok(listener)
+}
+
+/// The listening socket from a systemd `.socket` unit (`LISTEN_FDS`), if
+/// the proxy was started by one.
+pub fn activated_listener() -> std::io::Result<Option<TcpListener>> {
+    let mut fds = sd_notify::listen_fds()?;
+    let Some(fd) = fds.next() else {
+        return Ok(None);
+    };
+    if fds.next().is_some() {
+        warn!("systemd passed more than one socket, only using the first");
+    }
+    // Safety: the fd is ours from here on, as socket activation intends
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    info!(
+        "Listening on {} (socket from systemd)",
+        listener.local_addr()?
+    );
+    Ok(Some(listener))
+}
 
 pub async fn run_tcp_server(
     ctx: Arc<ProxyContext>,
-    addr: SocketAddr,
+    listener: TcpListener,
     rx: broadcast::Receiver<BluetoothLEAdvertisementResponse>,
 ) -> std::io::Result<()> {
-    let listener = TcpListener::bind(addr).await?;
-    info!("Listening on {addr}");
-
+    let mut clients = JoinSet::new();
+    let mut shutdown = ctx.shutdown.subscribe();
     loop {
-        let (stream, peer) = listener.accept().await?;
-        info!("New connection from {peer}");
-        let mut client_rx = rx.resubscribe();
-        let ctx = Arc::clone(&ctx);
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(ctx, stream, &mut client_rx).await {
-                warn!("Client error: {e:?}");
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                info!("New connection from {peer}");
+                let mut client_rx = rx.resubscribe();
+                let ctx = Arc::clone(&ctx);
+                clients.spawn(async move {
+                    if let Err(e) = handle_client(ctx, stream, &mut client_rx).await {
+                        warn!("Client {peer} error: {e}");
+                    }
+                });
             }
-        });
+            Some(_) = clients.join_next() => {}
+            true = shutting_down(&mut shutdown) => break,
+        }
+    }
+
+    // Each client has been asked to disconnect; give them the time that takes.
+    // New connections are refused meanwhile, except on a socket from systemd:
+    // that stays open, to be handed on when restarting in place, and queues
+    // connections for the next process (systemd holds it open regardless).
+    if ctx.listen_fd.is_some() {
+        let _ = listener.into_std().map(IntoRawFd::into_raw_fd);
+    } else {
+        drop(listener);
+    }
+    if !clients.is_empty() {
+        info!("Waiting for {} clients to disconnect", clients.len());
     }
+    let all_gone = async { while clients.join_next().await.is_some() {} };
+    if timeout(
+        SHUTDOWN_DISCONNECT_TIMEOUT + Duration::from_secs(1),
+        all_gone,
+    )
+    .await
+    .is_err()
+    {
+        warn!("Closing {} clients that did not disconnect", clients.len());
+    }
+    Ok(())
 }
 
 async fn handle_client(
     ctx: Arc<ProxyContext>,
-    mut stream: TcpStream,
+    stream: TcpStream,
     rx: &mut broadcast::Receiver<BluetoothLEAdvertisementResponse>,
 ) -> std::io::Result<()> {
-    let mut buf = BytesMut::with_capacity(1024);
+    let mut conn = ApiConnection::accept(&ctx, stream).await?;
+    let outbound = conn.outbound();
+    let _client = ClientCount::new(&ctx, conn.peer_addr(), outbound.clone());
 
+    let mut session = Session::default();
     let mut subscription_flags = SubscriptionFlags::none();
+    let mut log_subscription: Option<LogSubscription> = None;
+    // Set once the client takes service calls and events from us
+    let mut ha_events: Option<broadcast::Receiver<HomeassistantServiceResponse>> = None;
+    // Set once the client subscribes to entity states
+    let mut state_cache: Option<StateCache> = None;
+    // Set once the client sends us Home Assistant states for the rules
+    let mut rule_feed: Option<RuleFeed> = None;
+    let mut state_updates = interval(STATE_UPDATE_INTERVAL);
+    state_updates.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Set once we've sent DisconnectRequest; the client gets this long to answer
+    let mut disconnect_deadline: Option<Instant> = None;
+    // A client silent for a whole keepalive interval gets pinged, and is
+    // dropped after too many pings go unanswered
+    let mut keepalive = interval_at(
+        Instant::now() + ctx.keepalive_interval,
+        ctx.keepalive_interval,
+    );
+    keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut heard_from_client = false;
+    let mut missed_pings = 0;
+    // Scan settings changed by any client are reported to all of them
+    let mut scan_settings = ctx.scan_settings.subscribe();
+    let mut shutdown = ctx.shutdown.subscribe();
     loop {
         tokio::select! {
-            n = stream.read_buf(&mut buf) => {
+            n = conn.read() => {
                 match n {
                     Ok(0) => {
                         info!("Client closed connection");
                         break;
                     },
                     Ok(_) => {
-                        while let Some((msg_type, payload)) = next_message(&mut buf) {
-                            match msg_type {
-                                0x01 => hello_request(&mut stream, &payload).await?,
-                                0x03 => connect_request(&mut stream, &payload).await?,
-                                0x05 => disconnect_request(&mut stream, &payload).await?,
-                                0x07 => ping_request(&mut stream, &payload).await?,
-                                0x09 => device_info_request(ctx.clone(), &mut stream, &payload).await?,
-                                0x0b => list_entities_request(&mut stream, &payload).await?,
-                                0x42 => {
-                                    match subscribe_bluetooth_le_advertisements_request(&mut stream, &payload).await {
+                        heard_from_client = true;
+                        missed_pings = 0;
+                        while let Some((msg_type, payload)) = conn.next_message()? {
+                            match session.state.check(msg_type) {
+                                Verdict::Allowed => {}
+                                Verdict::Ignored => {
+                                    debug!("Ignoring message type {} while {:?}", msg_type, session.state);
+                                    continue;
+                                }
+                                Verdict::Refused(reason) => {
+                                    warn!("Closing connection from {}: {} (message type {}, state {:?})",
+                                          conn.peer_addr().ip(), reason, msg_type, session.state);
+                                    return Ok(());
+                                }
+                            }
+                            let Some(message) = ClientMessage::decode(msg_type, &payload)? else {
+                                log_unhandled(&conn, msg_type);
+                                continue;
+                            };
+                            match message {
+                                ClientMessage::Hello(req) => {
+                                    let hello = hello_request(&mut conn, req).await?;
+                                    match session.hello(&hello) {
+                                        Ok(()) => {
+                                            debug!("Negotiated API {}.{} with '{}'",
+                                                   session.api_version.0, session.api_version.1, session.client_info);
+                                            if let Some(client) = ctx.clients.lock().unwrap().get_mut(&conn.peer_addr()) {
+                                                client.name = session.client_info.clone();
+                                            }
+                                        },
+                                        Err(e) => {
+                                            warn!("Disconnecting {}: {e}", conn.peer_addr().ip());
+                                            send_disconnect_request(&mut conn).await?;
+                                            session.state = SessionState::Disconnecting;
+                                            disconnect_deadline = Some(Instant::now() + DISCONNECT_TIMEOUT);
+                                        }
+                                    }
+                                },
+                                ClientMessage::Connect(req) => {
+                                    if connect_request(ctx.clone(), &mut conn, req).await? {
+                                        session.state = SessionState::Connected;
+                                        if ctx.max_clock_skew.is_some() {
+                                            send_get_time_request(&mut conn).await?;
+                                        }
+                                    }
+                                },
+                                ClientMessage::Disconnect(req) => {
+                                    disconnect_request(&mut conn, req).await?;
+                                    return Ok(());
+                                },
+                                ClientMessage::DisconnectAck(_) => {
+                                    info!("{} acknowledged disconnect", conn.peer_addr().ip());
+                                    return Ok(());
+                                },
+                                ClientMessage::Ping(req) => ping_request(&mut conn, req).await?,
+                                ClientMessage::Pong(_) => debug!("Keepalive answered by {}", conn.peer_addr().ip()),
+                                ClientMessage::DeviceInfo(req) => device_info_request(ctx.clone(), &mut conn, req).await?,
+                                ClientMessage::GetTime(req) => get_time_request(&mut conn, req).await?,
+                                ClientMessage::Time(resp) => get_time_response(&ctx, &conn, resp),
+                                ClientMessage::SubscribeLogs(req) => {
+                                    log_subscription = subscribe_logs_request(ctx.clone(), &mut conn, req).await?;
+                                },
+                                ClientMessage::ListEntities(req) => list_entities_request(ctx.clone(), &mut conn, req).await?,
+                                ClientMessage::SubscribeHomeassistantServices(req) => {
+                                    ha_events = Some(subscribe_homeassistant_services_request(&ctx, &conn, req));
+                                },
+                                ClientMessage::SubscribeHomeAssistantStates(req) => {
+                                    subscribe_home_assistant_states_request(ctx.clone(), &mut conn, req).await?;
+                                    rule_feed.get_or_insert_with(|| RuleFeed::new(&ctx));
+                                },
+                                ClientMessage::HomeAssistantState(resp) => home_assistant_state_response(&ctx, &conn, resp),
+                                ClientMessage::SubscribeStates(req) => {
+                                    state_cache = Some(subscribe_states_request(ctx.clone(), &mut conn, req).await?);
+                                },
+                                ClientMessage::SubscribeAdvertisements(req) => {
+                                    match subscribe_bluetooth_le_advertisements_request(&ctx, &mut conn, req).await {
                                         Ok(sub_flags) => {
                                             subscription_flags = sub_flags;
                                         }
@@ -72,14 +244,17 @@ async fn handle_client(
                                         }
                                     }
                                 },
-                                0x50 => subscribe_bluetooth_connections_free_request(&mut stream, &payload).await?,
-                                0x57 => {
+                                ClientMessage::SubscribeConnectionsFree(req) => subscribe_bluetooth_connections_free_request(&mut conn, req).await?,
+                                ClientMessage::UnsubscribeAdvertisements(_) => {
                                     info!("Handling BLE Adv unsubscribe request");
                                     subscription_flags = SubscriptionFlags::none();
                                 },
-                                _ => {
-                                    warn!("Unknown message type: 0x{:02x} ({}) from {}", msg_type, msg_type, stream.peer_addr()?.ip());
-                                }
+                                ClientMessage::NoiseEncryptionSetKey(req) => noise_encryption_set_key_request(ctx.clone(), &mut conn, req).await?,
+                                ClientMessage::ButtonCommand(req) => button_command_request(ctx.clone(), &mut conn, req).await?,
+                                ClientMessage::ExecuteService(req) => execute_service_request(&ctx, &conn, req),
+                                ClientMessage::SwitchCommand(req) => switch_command_request(&ctx, &conn, req),
+                                ClientMessage::SelectCommand(req) => select_command_request(&ctx, &conn, req),
+                                ClientMessage::ScannerSetMode(req) => bluetooth_scanner_set_mode_request(&ctx, &conn, req),
                             }
                         }
                     },
@@ -92,14 +267,22 @@ async fn handle_client(
             ble_msg = rx.recv() => {
                 match ble_msg {
                     Ok(advert) => {
-                        if subscription_flags.is_subscribed() {
-                            debug!("Forwarding BLE advertisement to {} (flags: {:?})",
-                                   stream.peer_addr()?.ip(), subscription_flags);
-                            forward_ble_advertisement(&mut stream, advert).await?;
+                        let strong_enough = ctx.min_rssi().is_none_or(|min| advert.rssi >= min);
+                        if session.state == SessionState::Connected && subscription_flags.is_subscribed() {
+                            if strong_enough {
+                                debug!("Forwarding BLE advertisement to {} (flags: {:?})",
+                                       conn.peer_addr().ip(), subscription_flags);
+                                forward_ble_advertisement(&mut conn, &advert)?;
+                                ctx.outbound.adverts_forwarded.fetch_add(1, Ordering::Relaxed);
+                            } else {
+                                ctx.outbound.adverts_filtered.fetch_add(1, Ordering::Relaxed);
+                            }
                         }
                     },
                     Err(broadcast::error::RecvError::Lagged(n)) => {
                         warn!("Lagged behind on BLE broadcast: {n} messages dropped");
+                        ctx.outbound.adverts_dropped.fetch_add(n, Ordering::Relaxed);
+                        ctx.outbound.adverts_lagged.fetch_add(n, Ordering::Relaxed);
                     },
                     Err(broadcast::error::RecvError::Closed) => {
                         warn!("BLE broadcast channel closed");
@@ -107,7 +290,153 @@ async fn handle_client(
                     }
                 }
             }, // BLE Advertisement branch of select!
+            Some(line) = next_log_line(&mut log_subscription) => {
+                conn.send(&line).await?;
+            }, // Log subscription branch of select!
+            Some(event) = next_ha_event(&mut ha_events) => {
+                conn.send(&event).await?;
+            }, // Home Assistant event branch of select!
+            true = shutting_down(&mut shutdown), if disconnect_deadline.is_none() => {
+                if session.state == SessionState::AwaitingHello {
+                    break;
+                }
+                send_disconnect_request(&mut conn).await?;
+                session.state = SessionState::Disconnecting;
+                disconnect_deadline = Some(Instant::now() + SHUTDOWN_DISCONNECT_TIMEOUT);
+            }, // Shutdown branch of select!
+            _ = sleep_until(disconnect_deadline.unwrap_or_else(Instant::now)), if disconnect_deadline.is_some() => {
+                info!("{} did not answer DisconnectRequest, closing", conn.peer_addr().ip());
+                break;
+            }, // Disconnect timeout branch of select!
+            _ = state_updates.tick(), if state_cache.is_some() => {
+                if let Some(states) = &mut state_cache {
+                    send_state_updates(&ctx, &mut conn, states).await?;
+                }
+            }, // Entity state branch of select!
+            Ok(()) = scan_settings.changed() => {
+                if let Some(states) = &mut state_cache {
+                    send_state_updates(&ctx, &mut conn, states).await?;
+                }
+                if session.state == SessionState::Connected && subscription_flags.is_subscribed() {
+                    send_scanner_state(&ctx, &mut conn).await?;
+                }
+            }, // Scan settings branch of select!
+            _ = keepalive.tick() => {
+                if heard_from_client {
+                    heard_from_client = false;
+                } else if missed_pings >= ctx.keepalive_misses {
+                    warn!("{} missed {} keepalive pings, closing", conn.peer_addr().ip(), missed_pings);
+                    break;
+                } else {
+                    missed_pings += 1;
+                    // Before authentication the client is expected to be
+                    // talking; it gets the same grace period, but no pings
+                    if session.state == SessionState::Connected {
+                        send_ping_request(&mut conn).await?;
+                    }
+                }
+            }, // Keepalive branch of select!
+            _ = outbound.writer_failed() => {
+                info!("Dropping {}: connection writer stopped", conn.peer_addr().ip());
+                break;
+            }, // Writer failure branch of select!
         }
+        // Whatever the branch queued goes out together
+        conn.flush();
     }
     Ok(())
 }
+
+/// Counts a connection in `ProxyContext::api_clients`, and lists it in
+/// `ProxyContext::clients`, while it lives.
+struct ClientCount<'a>(&'a ProxyContext, SocketAddr);
+
+impl<'a> ClientCount<'a> {
+    fn new(ctx: &'a ProxyContext, peer: SocketAddr, outbound: Arc<Outbound>) -> Self {
+        ctx.api_clients.fetch_add(1, Ordering::Relaxed);
+        ctx.clients.lock().unwrap().insert(
+            peer,
+            ClientInfo {
+                name: String::new(),
+                since: std::time::Instant::now(),
+                outbound,
+            },
+        );
+        ClientCount(ctx, peer)
+    }
+}
+
+impl Drop for ClientCount<'_> {
+    fn drop(&mut self) {
+        self.0.clients.lock().unwrap().remove(&self.1);
+        self.0.api_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a connection feeding Home Assistant states to the rules. When
+/// the last one goes, nobody tells us when a state changes any more, so
+/// the rules stop applying rather than hold the scanner in their settings.
+struct RuleFeed<'a>(&'a ProxyContext);
+
+impl<'a> RuleFeed<'a> {
+    fn new(ctx: &'a ProxyContext) -> Self {
+        ctx.rules.add_feed();
+        RuleFeed(ctx)
+    }
+}
+
+impl Drop for RuleFeed<'_> {
+    fn drop(&mut self) {
+        if self.0.rules.remove_feed()
+            && self.0.scan_settings().overrides != ScanOverrides::default()
+        {
+            info!("No client reports Home Assistant states any more, rules no longer apply");
+            self.0.set_scan_overrides(ScanOverrides::default());
+        }
+    }
+}
+
+/// Resolves (to true) once the proxy starts shutting down.
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) -> bool {
+    shutdown.wait_for(|&shutdown| shutdown).await.is_ok()
+}
+
+async fn next_log_line(
+    subscription: &mut Option<LogSubscription>,
+) -> Option<SubscribeLogsResponse> {
+    match subscription {
+        Some(subscription) => subscription.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_ha_event(
+    events: &mut Option<broadcast::Receiver<HomeassistantServiceResponse>>,
+) -> Option<HomeassistantServiceResponse> {
+    let Some(events) = events else {
+        return std::future::pending().await;
+    };
+    loop {
+        match events.recv().await {
+            Ok(event) => return Some(event),
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("Dropped {n} Home Assistant events for a slow client")
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+fn log_unhandled(conn: &ApiConnection, msg_type: u32) {
+    let ip = conn.peer_addr().ip();
+    match dispatch::lookup(msg_type) {
+        Some(info) if !info.client_may_send() => {
+            warn!(
+                "{ip} sent {} ({msg_type}), which only the device should send",
+                info.name
+            )
+        }
+        Some(info) => debug!("Ignoring unsupported {} ({msg_type}) from {ip}", info.name),
+        None => warn!("Unknown message type: 0x{msg_type:02x} ({msg_type}) from {ip}"),
+    }
+}
@@ -0,0 +1,373 @@
+use log::{info, warn};
+use protobuf::EnumOrUnknown;
+use serde::Deserialize;
+use std::fmt;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+
+use crate::api::api::{
+    ExecuteServiceArgument, ListEntitiesServicesArgument, ListEntitiesServicesResponse,
+    ServiceArgType,
+};
+use crate::context::ProxyContext;
+use crate::entities::{entity_key, unique_id};
+
+/// A service still running after this long is killed.
+const SERVICE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct ServiceArg {
+    pub name: String,
+    pub arg_type: ServiceArgType,
+}
+
+/// An API service Home Assistant can call, running one fixed local command,
+/// written `NAME[(ARG:TYPE,...)]=COMMAND ARGS...`, e.g.
+/// `reset_adapter=hciconfig hci0 reset`. Home Assistant only chooses the
+/// arguments, which reach the command as `ARG_<NAME>` environment
+/// variables, never as part of its command line.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Service {
+    pub name: String,
+    pub args: Vec<ServiceArg>,
+    /// Program and its arguments
+    pub command: Vec<String>,
+    /// Set while the command runs; a service runs once at a time
+    running: Arc<AtomicBool>,
+}
+
+/// Clears `Service::running` when the run ends, however it ends.
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn parse_arg_type(name: &str) -> Option<ServiceArgType> {
+    Some(match name {
+        "bool" => ServiceArgType::SERVICE_ARG_TYPE_BOOL,
+        "int" => ServiceArgType::SERVICE_ARG_TYPE_INT,
+        "float" => ServiceArgType::SERVICE_ARG_TYPE_FLOAT,
+        "string" => ServiceArgType::SERVICE_ARG_TYPE_STRING,
+        "bool[]" => ServiceArgType::SERVICE_ARG_TYPE_BOOL_ARRAY,
+        "int[]" => ServiceArgType::SERVICE_ARG_TYPE_INT_ARRAY,
+        "float[]" => ServiceArgType::SERVICE_ARG_TYPE_FLOAT_ARRAY,
+        "string[]" => ServiceArgType::SERVICE_ARG_TYPE_STRING_ARRAY,
+        _ => return None,
+    })
+}
+
+impl FromStr for Service {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (signature, command) = text
+            .split_once('=')
+            .ok_or("expected NAME[(ARG:TYPE,...)]=COMMAND")?;
+        let (name, args) = match signature.trim().split_once('(') {
+            Some((name, args)) => (
+                name.trim(),
+                args.strip_suffix(')')
+                    .ok_or_else(|| format!("unclosed argument list in '{signature}'"))?,
+            ),
+            None => (signature.trim(), ""),
+        };
+        if !valid_name(name) {
+            return Err(format!(
+                "service name '{name}' may only have lowercase letters, digits and _"
+            ));
+        }
+
+        let mut parsed_args: Vec<ServiceArg> = Vec::new();
+        for arg in args.split(',').map(str::trim).filter(|arg| !arg.is_empty()) {
+            let (arg_name, type_name) = arg
+                .split_once(':')
+                .ok_or_else(|| format!("expected ARG:TYPE, got '{arg}'"))?;
+            let (arg_name, type_name) = (arg_name.trim(), type_name.trim());
+            if !valid_name(arg_name) {
+                return Err(format!(
+                    "argument name '{arg_name}' may only have lowercase letters, digits and _"
+                ));
+            }
+            if parsed_args.iter().any(|a| a.name == arg_name) {
+                return Err(format!("argument '{arg_name}' given twice"));
+            }
+            let arg_type = parse_arg_type(type_name).ok_or_else(|| {
+                format!("unknown type '{type_name}' (expected bool, int, float, string or an array of one, like int[])")
+            })?;
+            parsed_args.push(ServiceArg {
+                name: arg_name.to_string(),
+                arg_type,
+            });
+        }
+
+        let command: Vec<String> = command.split_whitespace().map(String::from).collect();
+        if command.is_empty() {
+            return Err(format!("no command given for service '{name}'"));
+        }
+
+        Ok(Service {
+            name: name.to_string(),
+            args: parsed_args,
+            command,
+            running: Arc::default(),
+        })
+    }
+}
+
+impl TryFrom<String> for Service {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// An argument's value as an environment variable. Arrays have one element
+/// per line.
+fn arg_value(arg_type: ServiceArgType, value: &ExecuteServiceArgument) -> String {
+    fn lines<T: ToString>(values: &[T]) -> String {
+        values
+            .iter()
+            .map(T::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    match arg_type {
+        ServiceArgType::SERVICE_ARG_TYPE_BOOL => value.bool_.to_string(),
+        ServiceArgType::SERVICE_ARG_TYPE_INT => value.int_.to_string(),
+        ServiceArgType::SERVICE_ARG_TYPE_FLOAT => value.float_.to_string(),
+        ServiceArgType::SERVICE_ARG_TYPE_STRING => value.string_.clone(),
+        ServiceArgType::SERVICE_ARG_TYPE_BOOL_ARRAY => lines(&value.bool_array),
+        ServiceArgType::SERVICE_ARG_TYPE_INT_ARRAY => lines(&value.int_array),
+        ServiceArgType::SERVICE_ARG_TYPE_FLOAT_ARRAY => lines(&value.float_array),
+        ServiceArgType::SERVICE_ARG_TYPE_STRING_ARRAY => value.string_array.join("\n"),
+    }
+}
+
+impl Service {
+    pub fn key(&self, ctx: &ProxyContext) -> u32 {
+        entity_key(&unique_id(ctx, "service", &self.name))
+    }
+
+    pub fn find(ctx: &ProxyContext, key: u32) -> Option<&Service> {
+        ctx.services.iter().find(|service| service.key(ctx) == key)
+    }
+
+    pub fn list_response(&self, ctx: &ProxyContext) -> ListEntitiesServicesResponse {
+        ListEntitiesServicesResponse {
+            name: self.name.clone(),
+            key: self.key(ctx),
+            args: self
+                .args
+                .iter()
+                .map(|arg| ListEntitiesServicesArgument {
+                    name: arg.name.clone(),
+                    type_: EnumOrUnknown::new(arg.arg_type),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Starts the command in the background with the call's arguments; its
+    /// output goes to the log, so clients following it see what happened.
+    /// Refused while the previous call's command is still running.
+    pub fn execute(&self, values: &[ExecuteServiceArgument]) -> Result<(), String> {
+        if values.len() != self.args.len() {
+            return Err(format!(
+                "expected {} arguments, got {}",
+                self.args.len(),
+                values.len()
+            ));
+        }
+        let env: Vec<(String, String)> = self
+            .args
+            .iter()
+            .zip(values)
+            .map(|(arg, value)| {
+                (
+                    format!("ARG_{}", arg.name.to_uppercase()),
+                    arg_value(arg.arg_type, value),
+                )
+            })
+            .collect();
+
+        if self.running.swap(true, Ordering::Relaxed) {
+            return Err("still running from the last call, not starting it again".to_string());
+        }
+        let running = Running(self.running.clone());
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("failed to start {}: {e}", self.command[0]))?;
+
+        let name = self.name.clone();
+        let stdout = child.stdout.take().map(|out| BufReader::new(out).lines());
+        let stderr = child.stderr.take().map(|err| BufReader::new(err).lines());
+        tokio::spawn(async move {
+            let _running = running;
+            let output = async {
+                if let (Some(mut stdout), Some(mut stderr)) = (stdout, stderr) {
+                    let (mut out_done, mut err_done) = (false, false);
+                    while !(out_done && err_done) {
+                        tokio::select! {
+                            line = stdout.next_line(), if !out_done => match line {
+                                Ok(Some(line)) => info!("{name}: {line}"),
+                                _ => out_done = true,
+                            },
+                            line = stderr.next_line(), if !err_done => match line {
+                                Ok(Some(line)) => warn!("{name}: {line}"),
+                                _ => err_done = true,
+                            },
+                        }
+                    }
+                }
+                child.wait().await
+            };
+            match timeout(SERVICE_TIMEOUT, output).await {
+                Ok(Ok(status)) if status.success() => info!("Service {name} finished"),
+                Ok(Ok(status)) => warn!("Service {name} failed: {status}"),
+                Ok(Err(e)) => warn!("Service {name} failed: {e}"),
+                // Dropping the child kills it
+                Err(_) => warn!(
+                    "Service {name} killed after running for {}s",
+                    SERVICE_TIMEOUT.as_secs()
+                ),
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_services() {
+        let service: Service = "reset_adapter=hciconfig hci0 reset".parse().unwrap();
+        assert_eq!(service.name, "reset_adapter");
+        assert!(service.args.is_empty());
+        assert_eq!(service.command, ["hciconfig", "hci0", "reset"]);
+
+        let service: Service =
+            " notify ( title:string, level : int, tags:string[] )=  /usr/bin/notify  -q"
+                .parse()
+                .unwrap();
+        assert_eq!(service.name, "notify");
+        assert_eq!(
+            service
+                .args
+                .iter()
+                .map(|arg| (arg.name.as_str(), arg.arg_type))
+                .collect::<Vec<_>>(),
+            [
+                ("title", ServiceArgType::SERVICE_ARG_TYPE_STRING),
+                ("level", ServiceArgType::SERVICE_ARG_TYPE_INT),
+                ("tags", ServiceArgType::SERVICE_ARG_TYPE_STRING_ARRAY),
+            ]
+        );
+        assert_eq!(service.command, ["/usr/bin/notify", "-q"]);
+
+        // Only the first = separates the command
+        let service: Service = "set()=env A=1 run".parse().unwrap();
+        assert!(service.args.is_empty());
+        assert_eq!(service.command, ["env", "A=1", "run"]);
+    }
+
+    #[test]
+    fn parses_every_argument_type() {
+        for (name, arg_type) in [
+            ("bool", ServiceArgType::SERVICE_ARG_TYPE_BOOL),
+            ("int", ServiceArgType::SERVICE_ARG_TYPE_INT),
+            ("float", ServiceArgType::SERVICE_ARG_TYPE_FLOAT),
+            ("string", ServiceArgType::SERVICE_ARG_TYPE_STRING),
+            ("bool[]", ServiceArgType::SERVICE_ARG_TYPE_BOOL_ARRAY),
+            ("int[]", ServiceArgType::SERVICE_ARG_TYPE_INT_ARRAY),
+            ("float[]", ServiceArgType::SERVICE_ARG_TYPE_FLOAT_ARRAY),
+            ("string[]", ServiceArgType::SERVICE_ARG_TYPE_STRING_ARRAY),
+        ] {
+            let service: Service = format!("s(x:{name})=true").parse().unwrap();
+            assert_eq!(service.args[0].arg_type, arg_type, "{name}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_services() {
+        for (text, error) in [
+            ("reset_adapter", "expected NAME[(ARG:TYPE,...)]=COMMAND"),
+            ("Reset=true", "may only have lowercase letters"),
+            ("=true", "may only have lowercase letters"),
+            ("reset(x:int=true", "unclosed argument list"),
+            ("reset(x)=true", "expected ARG:TYPE"),
+            ("reset(X:int)=true", "argument name 'X'"),
+            ("reset(x:int,x:bool)=true", "given twice"),
+            ("reset(x:long)=true", "unknown type 'long'"),
+            ("reset=  ", "no command given"),
+        ] {
+            let result = text.parse::<Service>();
+            assert!(
+                result.as_ref().is_err_and(|e| e.contains(error)),
+                "{text}: {:?}",
+                result.map(|service| service.command)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_once_at_a_time() {
+        let service: Service = "done=true".parse().unwrap();
+        service.running.store(true, Ordering::Relaxed);
+        assert!(service
+            .execute(&[])
+            .is_err_and(|e| e.contains("still running")));
+        service.running.store(false, Ordering::Relaxed);
+
+        service.execute(&[]).unwrap();
+        // Cleared once the command exits, which `true` does right away
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while service.running.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("still marked running");
+        service.execute(&[]).unwrap();
+    }
+
+    #[test]
+    fn checks_the_argument_count() {
+        let service: Service = "s(x:int)=true".parse().unwrap();
+        assert!(service
+            .execute(&[])
+            .is_err_and(|e| e.contains("expected 1 arguments, got 0")));
+    }
+}
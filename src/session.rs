@@ -0,0 +1,232 @@
+use crate::api::api::{DisconnectRequest, DisconnectResponse, HelloRequest};
+use crate::dispatch::{self, message_info};
+
+/// API version this proxy speaks, sent in HelloResponse
+pub const API_VERSION_MAJOR: u32 = 1;
+pub const API_VERSION_MINOR: u32 = 10;
+
+/// Where a client connection is in the ESPHome API lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// Connection (and handshake, if encrypted) done, waiting for HelloRequest
+    AwaitingHello,
+    /// Hello exchanged, waiting for a ConnectRequest with the right password
+    AwaitingConnect,
+    /// Authenticated: every message is legal
+    Connected,
+    /// DisconnectRequest sent or received: only the response is still expected
+    Disconnecting,
+}
+
+/// What to do with an incoming message in the current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// Silently dropped, e.g. requests racing our DisconnectRequest
+    Ignored,
+    /// Protocol violation: the connection is closed, as ESPHome firmware does
+    Refused(&'static str),
+}
+
+impl SessionState {
+    pub fn check(self, msg_type: u32) -> Verdict {
+        // Requirements come from the rpc options in api.proto; ids it doesn't
+        // know need the full session, like any other request
+        let info = dispatch::lookup(msg_type);
+        match self {
+            SessionState::AwaitingHello if info.is_none_or(|i| i.needs_setup_connection) => {
+                Verdict::Refused("message before HelloRequest")
+            }
+            SessionState::AwaitingConnect if info.is_none_or(|i| i.needs_authentication) => {
+                Verdict::Refused("access without authentication")
+            }
+            // Only the peer's DisconnectResponse, or its own request, still matter
+            SessionState::Disconnecting
+                if msg_type != message_info::<DisconnectRequest>().id
+                    && msg_type != message_info::<DisconnectResponse>().id =>
+            {
+                Verdict::Ignored
+            }
+            _ => Verdict::Allowed,
+        }
+    }
+}
+
+pub struct Session {
+    pub state: SessionState,
+    pub client_info: String,
+    /// Version agreed with the client: our major, the lower of the two minors
+    pub api_version: (u32, u32),
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            state: SessionState::AwaitingHello,
+            client_info: String::new(),
+            api_version: (API_VERSION_MAJOR, API_VERSION_MINOR),
+        }
+    }
+}
+
+impl Session {
+    /// Records the client's HelloRequest and negotiates the API version. A
+    /// different major version is a breaking protocol change, so the session
+    /// can't continue; clients that predate versioning send 0.
+    pub fn hello(&mut self, req: &HelloRequest) -> Result<(), String> {
+        self.client_info = req.client_info.clone();
+        if req.api_version_major != 0 && req.api_version_major != API_VERSION_MAJOR {
+            return Err(format!(
+                "client API version {}.{} is incompatible with {}.{}",
+                req.api_version_major, req.api_version_minor, API_VERSION_MAJOR, API_VERSION_MINOR
+            ));
+        }
+        if req.api_version_major != 0 {
+            self.api_version = (
+                API_VERSION_MAJOR,
+                req.api_version_minor.min(API_VERSION_MINOR),
+            );
+        }
+        // A ConnectRequest sent before the hello may already have authenticated us
+        if self.state == SessionState::AwaitingHello {
+            self.state = SessionState::AwaitingConnect;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::api::{
+        ConnectRequest, DeviceInfoRequest, GetTimeRequest, ListEntitiesRequest, PingRequest,
+        SubscribeBluetoothLEAdvertisementsRequest,
+    };
+    use protobuf::MessageFull;
+
+    fn id<M: MessageFull>() -> u32 {
+        message_info::<M>().id
+    }
+
+    // Not in api.proto
+    const UNKNOWN: u32 = 60_000;
+
+    #[test]
+    fn awaiting_hello_allows_only_the_setup_messages() {
+        let state = SessionState::AwaitingHello;
+        for allowed in [
+            id::<HelloRequest>(),
+            id::<ConnectRequest>(),
+            id::<PingRequest>(),
+            id::<DisconnectRequest>(),
+        ] {
+            assert_eq!(state.check(allowed), Verdict::Allowed, "{allowed}");
+        }
+        for refused in [
+            id::<DeviceInfoRequest>(),
+            id::<ListEntitiesRequest>(),
+            UNKNOWN,
+        ] {
+            assert_eq!(
+                state.check(refused),
+                Verdict::Refused("message before HelloRequest"),
+                "{refused}"
+            );
+        }
+    }
+
+    #[test]
+    fn awaiting_connect_refuses_what_needs_authentication() {
+        let state = SessionState::AwaitingConnect;
+        for allowed in [
+            id::<ConnectRequest>(),
+            id::<PingRequest>(),
+            id::<DeviceInfoRequest>(),
+            id::<GetTimeRequest>(),
+        ] {
+            assert_eq!(state.check(allowed), Verdict::Allowed, "{allowed}");
+        }
+        for refused in [
+            id::<ListEntitiesRequest>(),
+            id::<SubscribeBluetoothLEAdvertisementsRequest>(),
+            UNKNOWN,
+        ] {
+            assert_eq!(
+                state.check(refused),
+                Verdict::Refused("access without authentication"),
+                "{refused}"
+            );
+        }
+    }
+
+    #[test]
+    fn connected_allows_everything() {
+        for msg_type in [
+            id::<HelloRequest>(),
+            id::<ListEntitiesRequest>(),
+            id::<SubscribeBluetoothLEAdvertisementsRequest>(),
+            UNKNOWN,
+        ] {
+            assert_eq!(
+                SessionState::Connected.check(msg_type),
+                Verdict::Allowed,
+                "{msg_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn disconnecting_only_expects_the_disconnect() {
+        let state = SessionState::Disconnecting;
+        assert_eq!(state.check(id::<DisconnectRequest>()), Verdict::Allowed);
+        assert_eq!(state.check(id::<DisconnectResponse>()), Verdict::Allowed);
+        for ignored in [id::<PingRequest>(), id::<ListEntitiesRequest>(), UNKNOWN] {
+            assert_eq!(state.check(ignored), Verdict::Ignored, "{ignored}");
+        }
+    }
+
+    fn hello(major: u32, minor: u32) -> HelloRequest {
+        HelloRequest {
+            client_info: "Home Assistant".to_string(),
+            api_version_major: major,
+            api_version_minor: minor,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hello_negotiates_the_lower_minor() {
+        let mut session = Session::default();
+        session.hello(&hello(API_VERSION_MAJOR, 99)).unwrap();
+        assert_eq!(session.api_version, (API_VERSION_MAJOR, API_VERSION_MINOR));
+        assert_eq!(session.client_info, "Home Assistant");
+        assert_eq!(session.state, SessionState::AwaitingConnect);
+
+        let mut session = Session::default();
+        session.hello(&hello(API_VERSION_MAJOR, 3)).unwrap();
+        assert_eq!(session.api_version, (API_VERSION_MAJOR, 3));
+
+        // Clients from before versioning get ours
+        let mut session = Session::default();
+        session.hello(&hello(0, 0)).unwrap();
+        assert_eq!(session.api_version, (API_VERSION_MAJOR, API_VERSION_MINOR));
+    }
+
+    #[test]
+    fn hello_refuses_another_major_version() {
+        let mut session = Session::default();
+        let e = session.hello(&hello(API_VERSION_MAJOR + 1, 0)).unwrap_err();
+        assert!(e.contains("incompatible"), "{e}");
+        assert_eq!(session.state, SessionState::AwaitingHello);
+    }
+
+    #[test]
+    fn hello_after_connect_keeps_the_session() {
+        let mut session = Session {
+            state: SessionState::Connected,
+            ..Default::default()
+        };
+        session.hello(&hello(API_VERSION_MAJOR, 1)).unwrap();
+        assert_eq!(session.state, SessionState::Connected);
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::ble::ScanSettings;
+use crate::noise::decode_psk;
+
+const NOISE_KEY_FILE: &str = "noise_key";
+const SCAN_SETTINGS_FILE: &str = "scan_settings.json";
+
+/// Settings the proxy changes at runtime and keeps across restarts, one small
+/// file each in the state directory.
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(dir: PathBuf) -> Self {
+        StateStore { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The encryption key last provisioned by Home Assistant, if any.
+    pub fn load_noise_key(&self) -> Result<Option<[u8; 32]>> {
+        let path = self.dir.join(NOISE_KEY_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(encoded) => decode_psk(&encoded)
+                .map(Some)
+                .with_context(|| format!("Invalid encryption key in {}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Reading {}", path.display())),
+        }
+    }
+
+    pub fn save_noise_key(&self, key: &[u8; 32]) -> Result<()> {
+        self.write_atomic(NOISE_KEY_FILE, STANDARD.encode(key).as_bytes())
+    }
+
+    /// Scan settings last chosen from Home Assistant, if any.
+    pub fn load_scan_settings(&self) -> Result<Option<ScanSettings>> {
+        let path = self.dir.join(SCAN_SETTINGS_FILE);
+        match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .with_context(|| format!("Invalid scan settings in {}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Reading {}", path.display())),
+        }
+    }
+
+    pub fn save_scan_settings(&self, settings: &ScanSettings) -> Result<()> {
+        self.write_atomic(SCAN_SETTINGS_FILE, &serde_json::to_vec(settings)?)
+    }
+
+    /// Replaces `name` in the state directory so that readers (and a crash
+    /// at any point) see either the old or the new contents, never a mix.
+    /// Files are only readable by the daemon's user since they may hold keys.
+    fn write_atomic(&self, name: &str, contents: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        let tmp = self.dir.join(format!(".{name}.tmp"));
+
+        // A leftover from an interrupted write might have other permissions
+        let _ = std::fs::remove_file(&tmp);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("Creating {}", tmp.display()))?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        drop(file);
+
+        std::fs::rename(&tmp, &path).with_context(|| format!("Replacing {}", path.display()))?;
+        // Make the rename itself durable
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+use anyhow::{bail, Result};
+use log::{debug, info};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+
+use crate::api::api::{BluetoothLEAdvertisementResponse, BluetoothServiceData};
+use crate::proto::{encode_response, get_message_id};
+
+const APPLE_COMPANY_ID: u16 = 0x004c;
+const BTHOME_UUID: &str = "0000fcd2-0000-1000-8000-00805f9b34fb";
+
+// How often the generator wakes up to send the adverts that are due
+const TICK: Duration = Duration::from_millis(10);
+// Advertisements per second `--synthetic-rate` accepts
+const RATES: RangeInclusive<f64> = 0.1..=1_000_000.0;
+// Most sent per tick, the top rate's share; a generator that falls behind
+// catches up over the following ticks rather than holding a worker
+const MAX_PER_TICK: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceKind {
+    IBeacon,
+    BtHome,
+    Random,
+}
+
+/// How many fake devices of each kind to simulate.
+#[derive(Debug, Clone, Default)]
+pub struct Population {
+    pub ibeacon: usize,
+    pub bthome: usize,
+    pub random: usize,
+}
+
+impl Population {
+    fn total(&self) -> usize {
+        self.ibeacon + self.bthome + self.random
+    }
+}
+
+/// Parses a population spec such as `ibeacon=10,bthome=5,random=100`.
+pub fn parse_population(s: &str) -> Result<Population, String> {
+    let mut population = Population::default();
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (kind, count) = part
+            .split_once('=')
+            .ok_or_else(|| format!("Expected KIND=COUNT, got '{part}'"))?;
+        let count: usize = count
+            .parse()
+            .map_err(|_| format!("Invalid device count: '{count}'"))?;
+        match kind {
+            "ibeacon" => population.ibeacon = count,
+            "bthome" => population.bthome = count,
+            "random" => population.random = count,
+            _ => {
+                return Err(format!(
+                    "Unknown device kind '{kind}' (expected ibeacon, bthome or random)"
+                ))
+            }
+        }
+    }
+    if population.total() == 0 {
+        return Err("Synthetic population is empty".to_string());
+    }
+    Ok(population)
+}
+
+/// Parses `--synthetic-rate`, in advertisements per second.
+pub fn parse_synthetic_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s
+        .parse()
+        .map_err(|_| format!("Invalid advertisement rate: '{s}'"))?;
+    if !RATES.contains(&rate) {
+        return Err(format!(
+            "Advertisement rate must be between {} and {}, got '{s}'",
+            RATES.start(),
+            RATES.end()
+        ));
+    }
+    Ok(rate)
+}
+
+/// Small xorshift generator: the load generator needs speed and
+/// reproducibility, not cryptographic quality.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        XorShift(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for b in buf {
+            *b = self.next() as u8;
+        }
+    }
+}
+
+struct FakeDevice {
+    kind: DeviceKind,
+    address: u64,
+    base_rssi: i32,
+    /// Fixed part of the payload (beacon UUID, company id, ...)
+    seed: [u8; 20],
+    counter: u16,
+}
+
+impl FakeDevice {
+    fn new(kind: DeviceKind, rng: &mut XorShift) -> Self {
+        // Random static address: two most significant bits set
+        let address = (rng.next() & 0x3fff_ffff_ffff) | 0xc000_0000_0000;
+        let mut seed = [0u8; 20];
+        rng.fill(&mut seed);
+        FakeDevice {
+            kind,
+            address,
+            base_rssi: -95 + rng.below(60) as i32,
+            seed,
+            counter: 0,
+        }
+    }
+
+    fn advertise(&mut self, rng: &mut XorShift) -> BluetoothLEAdvertisementResponse {
+        self.counter = self.counter.wrapping_add(1);
+        let rssi = (self.base_rssi + rng.below(11) as i32 - 5).clamp(-127, -20);
+
+        let mut advert = BluetoothLEAdvertisementResponse {
+            address: self.address,
+            address_type: 1,
+            rssi,
+            ..Default::default()
+        };
+
+        match self.kind {
+            DeviceKind::IBeacon => {
+                // type, length, proximity UUID, major, minor, measured power
+                let mut data = vec![0x02, 0x15];
+                data.extend_from_slice(&self.seed[..16]);
+                data.extend_from_slice(&self.seed[16..20]);
+                data.push(0xc5);
+                advert.manufacturer_data.push(BluetoothServiceData {
+                    uuid: APPLE_COMPANY_ID.to_string(),
+                    data,
+                    ..Default::default()
+                });
+            }
+            DeviceKind::BtHome => {
+                // BTHome v2, unencrypted: packet id, battery, temperature, humidity
+                let temperature = 1500 + rng.below(1500) as i16;
+                let humidity = 3000 + rng.below(5000) as u16;
+                let mut data = vec![
+                    0x40,
+                    0x00,
+                    self.counter as u8,
+                    0x01,
+                    50 + (self.seed[0] % 50),
+                ];
+                data.push(0x02);
+                data.extend_from_slice(&temperature.to_le_bytes());
+                data.push(0x03);
+                data.extend_from_slice(&humidity.to_le_bytes());
+                advert.service_data.push(BluetoothServiceData {
+                    uuid: BTHOME_UUID.to_string(),
+                    data,
+                    ..Default::default()
+                });
+                advert.name = format!("BTHome {:04X}", self.address & 0xffff).into_bytes();
+            }
+            DeviceKind::Random => {
+                let company = u16::from_le_bytes([self.seed[0], self.seed[1]]);
+                let mut data = vec![0u8; 4 + rng.below(21) as usize];
+                rng.fill(&mut data);
+                advert.manufacturer_data.push(BluetoothServiceData {
+                    uuid: company.to_string(),
+                    data,
+                    ..Default::default()
+                });
+            }
+        }
+        advert
+    }
+}
+
+/// Counters shared between the generator and the bench reporter.
+#[derive(Default)]
+pub struct SyntheticStats {
+    pub generated: AtomicU64,
+}
+
+/// Generates advertisements from a population of fake devices at `rate`
+/// advertisements per second, spread round-robin over the devices.
+pub async fn run_synthetic_source(
+    population: Population,
+    rate: f64,
+    stats: Arc<SyntheticStats>,
+    tx: Sender<BluetoothLEAdvertisementResponse>,
+) -> Result<()> {
+    if rate <= 0.0 {
+        bail!("Synthetic advertisement rate must be positive");
+    }
+
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(1);
+    let mut rng = XorShift::new(seed);
+
+    let kinds = [
+        (DeviceKind::IBeacon, population.ibeacon),
+        (DeviceKind::BtHome, population.bthome),
+        (DeviceKind::Random, population.random),
+    ];
+    let mut devices: Vec<FakeDevice> = kinds
+        .iter()
+        .flat_map(|&(kind, count)| std::iter::repeat_n(kind, count))
+        .map(|kind| FakeDevice::new(kind, &mut rng))
+        .collect();
+
+    info!(
+        "Generating synthetic advertisements: {:?}, {rate} adv/s",
+        population
+    );
+
+    let start = Instant::now();
+    let mut sent: u64 = 0;
+    let mut next_device = 0;
+    let mut ticker = interval(TICK);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        ticker.tick().await;
+        // Send whatever is due by now, so high rates don't depend on timer resolution
+        let due = (start.elapsed().as_secs_f64() * rate) as u64;
+        let due = due.min(sent + MAX_PER_TICK);
+        while sent < due {
+            let advert = devices[next_device].advertise(&mut rng);
+            next_device = (next_device + 1) % devices.len();
+            if let Err(e) = tx.send(advert) {
+                debug!("No receivers for synthetic advertisement: {e}");
+            }
+            sent += 1;
+            stats.generated.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Default)]
+struct BenchClient {
+    received: AtomicU64,
+    dropped: AtomicU64,
+    lag: AtomicU64,
+}
+
+/// Bench mode: simulated API clients that subscribe to the advertisement
+/// channel and encode every advert as if writing it to a socket. Every
+/// `report_every` a line with throughput, per-client lag (adverts queued but
+/// not yet read) and drops (`RecvError::Lagged`) is logged.
+pub async fn run_bench(
+    clients: usize,
+    report_every: Duration,
+    stats: Arc<SyntheticStats>,
+    tx: Sender<BluetoothLEAdvertisementResponse>,
+) {
+    let bench_clients: Vec<Arc<BenchClient>> = (0..clients)
+        .map(|_| Arc::new(BenchClient::default()))
+        .collect();
+
+    for client in &bench_clients {
+        tokio::spawn(run_bench_client(tx.subscribe(), Arc::clone(client)));
+    }
+    info!("Bench mode: {clients} simulated clients, reporting every {report_every:?}");
+
+    let mut ticker = interval(report_every);
+    ticker.tick().await;
+    let mut last_generated = 0;
+    let mut last_received = vec![0u64; clients];
+    let mut last_report = Instant::now();
+
+    loop {
+        ticker.tick().await;
+        let elapsed = last_report.elapsed().as_secs_f64();
+        last_report = Instant::now();
+
+        let generated = stats.generated.load(Ordering::Relaxed);
+        info!(
+            "bench: generated {:.0} adv/s ({generated} total)",
+            (generated - last_generated) as f64 / elapsed
+        );
+        last_generated = generated;
+
+        for (i, client) in bench_clients.iter().enumerate() {
+            let received = client.received.load(Ordering::Relaxed);
+            info!(
+                "bench: client {i}: {:.0} adv/s, lag {}, dropped {}",
+                (received - last_received[i]) as f64 / elapsed,
+                client.lag.load(Ordering::Relaxed),
+                client.dropped.load(Ordering::Relaxed)
+            );
+            last_received[i] = received;
+        }
+    }
+}
+
+async fn run_bench_client(
+    mut rx: Receiver<BluetoothLEAdvertisementResponse>,
+    client: Arc<BenchClient>,
+) {
+    let msg_type = get_message_id::<BluetoothLEAdvertisementResponse>() as u32;
+    loop {
+        match rx.recv().await {
+            Ok(advert) => {
+                // Same work a real client does before the socket write
+                if encode_response(msg_type, &advert).is_ok() {
+                    client.received.fetch_add(1, Ordering::Relaxed);
+                }
+                client.lag.store(rx.len() as u64, Ordering::Relaxed);
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                client.dropped.fetch_add(n, Ordering::Relaxed);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_populations() {
+        let population = parse_population("ibeacon=10, bthome=5,random=100,").unwrap();
+        assert_eq!(
+            (population.ibeacon, population.bthome, population.random),
+            (10, 5, 100)
+        );
+        assert_eq!(population.total(), 115);
+
+        let population = parse_population("random=3").unwrap();
+        assert_eq!(
+            (population.ibeacon, population.bthome, population.random),
+            (0, 0, 3)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_populations() {
+        for (text, error) in [
+            ("ibeacon=1,eddystone=2", "Unknown device kind 'eddystone'"),
+            ("random", "Expected KIND=COUNT, got 'random'"),
+            ("random=-1", "Invalid device count: '-1'"),
+            ("random=many", "Invalid device count: 'many'"),
+            ("random=", "Invalid device count: ''"),
+            ("", "Synthetic population is empty"),
+            ("ibeacon=0,random=0", "Synthetic population is empty"),
+        ] {
+            let e = parse_population(text).unwrap_err();
+            assert!(e.contains(error), "{text}: {e}");
+        }
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_synthetic_rate("100"), Ok(100.0));
+        assert_eq!(parse_synthetic_rate("0.1"), Ok(0.1));
+        assert_eq!(parse_synthetic_rate("1e6"), Ok(1_000_000.0));
+        for bad in ["NaN", "inf", "0", "-5", "0.01", "1000001", "fast"] {
+            assert!(parse_synthetic_rate(bad).is_err(), "{bad}");
+        }
+    }
+}
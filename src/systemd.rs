@@ -0,0 +1,89 @@
+use log::{debug, info, warn};
+use sd_notify::NotifyState;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+
+use crate::context::ProxyContext;
+
+// How often the STATUS= line is refreshed
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Outside a `Type=notify` unit there's no NOTIFY_SOCKET, and this does nothing.
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        debug!("Failed to notify systemd: {e}");
+    }
+}
+
+/// The advertisement source, mDNS and the API listener are all up.
+pub fn notify_ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Keeps systemd's status line current, and with `WatchdogSec=` pets the
+/// watchdog as long as advertisements keep arriving (or scanning was turned
+/// off on purpose). A scanner that wedges without an error then stops the
+/// pings, and systemd restarts the proxy.
+pub async fn run_notify(ctx: Arc<ProxyContext>) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    let mut watchdog_usec = 0;
+    let watchdog = sd_notify::watchdog_enabled(false, &mut watchdog_usec)
+        .then(|| Duration::from_micros(watchdog_usec));
+    if let Some(timeout) = watchdog {
+        info!("systemd watchdog enabled, timeout {}s", timeout.as_secs());
+    }
+
+    let period = watchdog.map_or(STATUS_INTERVAL, |timeout| {
+        (timeout / 4).min(STATUS_INTERVAL)
+    });
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut received = ctx.tracker.adverts_received();
+    let mut last_progress = Instant::now();
+    let mut stalled = false;
+    loop {
+        ticks.tick().await;
+        let now_received = ctx.tracker.adverts_received();
+        if now_received != received || !ctx.scan_settings().is_scanning() {
+            last_progress = Instant::now();
+        }
+        received = now_received;
+
+        let status = format!(
+            "{} API clients, {} devices, {:.1} advertisements/s, {} received",
+            ctx.api_clients.load(Ordering::Relaxed),
+            ctx.tracker.known_devices(),
+            ctx.tracker.adverts_per_second(),
+            received
+        );
+        let Some(timeout) = watchdog else {
+            notify(&[NotifyState::Status(&status)]);
+            continue;
+        };
+        // Stop pinging well before systemd gives up, so it's the stall that
+        // triggers the restart rather than a slow tick
+        if last_progress.elapsed() < timeout / 2 {
+            if stalled {
+                info!("Advertisements are arriving again, resuming systemd watchdog pings");
+                stalled = false;
+            }
+            notify(&[NotifyState::Status(&status), NotifyState::Watchdog]);
+        } else {
+            if !stalled {
+                warn!(
+                    "No advertisements for {}s, no longer pinging the systemd watchdog",
+                    last_progress.elapsed().as_secs()
+                );
+                stalled = true;
+            }
+            notify(&[NotifyState::Status(&format!("Stalled: {status}"))]);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{Duration, Instant};
+
+use crate::api::api::BluetoothLEAdvertisementResponse;
+
+/// A device not heard from for this long is no longer counted as known
+pub const DEVICE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Seconds the advertisement rate is averaged over
+const RATE_WINDOW_SECS: u64 = 10;
+// Recent RSSI readings kept for the noise floor estimate
+const RSSI_SAMPLES: usize = 512;
+
+/// What was last heard from one device.
+#[derive(Clone)]
+pub struct SeenDevice {
+    pub last_seen: Instant,
+    /// The last name it sent; most devices only send theirs now and then
+    pub name: Vec<u8>,
+    pub advert: BluetoothLEAdvertisementResponse,
+}
+
+struct TrackerState {
+    /// Each address heard within about `DEVICE_TIMEOUT`
+    devices: HashMap<u64, SeenDevice>,
+    /// Advertisements per second since `epoch`, for the last few seconds
+    counts: VecDeque<(u64, u32)>,
+    rssi: VecDeque<i32>,
+    /// Advertisements recorded since startup
+    received: u64,
+}
+
+/// What the proxy has heard lately, across every client: fed from the
+/// advertisement broadcast, read by diagnostics.
+pub struct DeviceTracker {
+    epoch: Instant,
+    state: Mutex<TrackerState>,
+}
+
+impl Default for DeviceTracker {
+    fn default() -> Self {
+        DeviceTracker {
+            epoch: Instant::now(),
+            state: Mutex::new(TrackerState {
+                devices: HashMap::new(),
+                counts: VecDeque::new(),
+                rssi: VecDeque::with_capacity(RSSI_SAMPLES),
+                received: 0,
+            }),
+        }
+    }
+}
+
+impl DeviceTracker {
+    fn second(&self) -> u64 {
+        self.epoch.elapsed().as_secs()
+    }
+
+    pub fn record(&self, advert: &BluetoothLEAdvertisementResponse) {
+        let second = self.second();
+        let mut state = self.state.lock().unwrap();
+        let name = match state.devices.remove(&advert.address) {
+            Some(seen) if advert.name.is_empty() => seen.name,
+            _ => advert.name.clone(),
+        };
+        state.devices.insert(
+            advert.address,
+            SeenDevice {
+                last_seen: Instant::now(),
+                name,
+                advert: advert.clone(),
+            },
+        );
+        state.received += 1;
+        match state.counts.back_mut() {
+            Some((s, count)) if *s == second => *count += 1,
+            _ => {
+                state.counts.push_back((second, 1));
+                // Once a second is plenty to keep the map from growing
+                state
+                    .devices
+                    .retain(|_, seen| seen.last_seen.elapsed() <= DEVICE_TIMEOUT);
+            }
+        }
+        while state
+            .counts
+            .front()
+            .is_some_and(|&(s, _)| second - s > RATE_WINDOW_SECS)
+        {
+            state.counts.pop_front();
+        }
+        if state.rssi.len() == RSSI_SAMPLES {
+            state.rssi.pop_front();
+        }
+        state.rssi.push_back(advert.rssi);
+    }
+
+    /// How long advertisements have been tracked; until `DEVICE_TIMEOUT`
+    /// has passed, a device not heard yet may just be advertising slowly.
+    pub fn running_for(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+
+    /// When `address` was last heard, if within about `DEVICE_TIMEOUT`.
+    pub fn last_seen(&self, address: u64) -> Option<Instant> {
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .get(&address)
+            .map(|seen| seen.last_seen)
+    }
+
+    pub fn seen_recently(&self, address: u64) -> bool {
+        self.last_seen(address)
+            .is_some_and(|seen| seen.elapsed() <= DEVICE_TIMEOUT)
+    }
+
+    /// Devices heard within `DEVICE_TIMEOUT`; older ones are forgotten.
+    pub fn known_devices(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state
+            .devices
+            .retain(|_, seen| seen.last_seen.elapsed() <= DEVICE_TIMEOUT);
+        state.devices.len()
+    }
+
+    /// Devices heard within `DEVICE_TIMEOUT`, by address.
+    pub fn devices(&self) -> Vec<SeenDevice> {
+        let mut devices: Vec<SeenDevice> = self
+            .state
+            .lock()
+            .unwrap()
+            .devices
+            .values()
+            .filter(|seen| seen.last_seen.elapsed() <= DEVICE_TIMEOUT)
+            .cloned()
+            .collect();
+        devices.sort_by_key(|seen| seen.advert.address);
+        devices
+    }
+
+    pub fn adverts_received(&self) -> u64 {
+        self.state.lock().unwrap().received
+    }
+
+    /// Average over the last complete seconds; the current one is still filling.
+    pub fn adverts_per_second(&self) -> f32 {
+        let second = self.second();
+        let state = self.state.lock().unwrap();
+        let recent: u32 = state
+            .counts
+            .iter()
+            .filter(|&&(s, _)| s < second && second - s <= RATE_WINDOW_SECS)
+            .map(|&(_, count)| count)
+            .sum();
+        // Right after startup the window isn't full yet
+        match second.min(RATE_WINDOW_SECS) {
+            0 => 0.0,
+            seconds => recent as f32 / seconds as f32,
+        }
+    }
+
+    /// Estimate of the adapter's noise floor: the weakest signals it still
+    /// decodes, taken as the 5th percentile of recent RSSI readings.
+    pub fn rssi_noise_floor(&self) -> Option<f32> {
+        let state = self.state.lock().unwrap();
+        if state.rssi.is_empty() {
+            return None;
+        }
+        let mut readings: Vec<i32> = state.rssi.iter().copied().collect();
+        readings.sort_unstable();
+        Some(readings[readings.len() / 20] as f32)
+    }
+}
+
+/// Feeds every advertisement to the tracker.
+pub async fn run_tracker(
+    tracker: Arc<DeviceTracker>,
+    mut rx: broadcast::Receiver<BluetoothLEAdvertisementResponse>,
+) {
+    loop {
+        match rx.recv().await {
+            Ok(advert) => tracker.record(&advert),
+            Err(RecvError::Lagged(n)) => warn!("Device tracker missed {n} advertisements"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use libc::{self, c_int, c_uint, c_ulong, c_ushort, c_void};
 use std::mem::zeroed;
+use std::os::fd::RawFd;
 
 pub fn get_bt_mac(hci_index: u16) -> Option<[u8; 6]> {
     // These are constants from BlueZ / Bluetooth headers
@@ -66,6 +67,18 @@ pub fn format_mac(mac: &[u8], sep: &str) -> String {
         .join(sep)
 }
 
+/// Packs a MAC into the `u64` the API uses for Bluetooth addresses.
+pub fn mac_to_address(mac: [u8; 6]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes[2..].copy_from_slice(&mac);
+    u64::from_be_bytes(bytes)
+}
+
+/// The MAC of an API Bluetooth address, as `mac_to_address` packed it.
+pub fn address_to_mac(address: u64) -> [u8; 6] {
+    address.to_be_bytes()[2..].try_into().unwrap()
+}
+
 pub fn parse_mac(s: &str) -> Result<[u8; 6], String> {
     let parts: Vec<&str> = s.split(':').collect();
     if parts.len() != 6 {
@@ -78,3 +91,50 @@ pub fn parse_mac(s: &str) -> Result<[u8; 6], String> {
     }
     Ok(mac)
 }
+
+/// Compares secrets without leaking how many leading bytes matched through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Replaces the running process with a fresh copy of itself, same
+/// arguments and environment. Only returns if that fails. argv[0] rather
+/// than /proc/self/exe, so a binary replaced by an upgrade is picked up.
+/// A socket systemd passed in (`listen_fd`) is passed on the same way.
+pub fn reexec(listen_fd: Option<RawFd>) -> std::io::Error {
+    use std::os::unix::process::CommandExt;
+
+    let mut args = std::env::args_os();
+    let program = args.next().unwrap_or_else(|| env!("CARGO_PKG_NAME").into());
+    let mut command = std::process::Command::new(program);
+    command.args(args);
+    if let Some(fd) = listen_fd {
+        // Still fd 3, where systemd put it; it just needs to survive the exec
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } == -1 {
+            return std::io::Error::last_os_error();
+        }
+        command
+            .env("LISTEN_FDS", "1")
+            .env("LISTEN_PID", std::process::id().to_string());
+    }
+    command.exec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_round_trip() {
+        let mac = parse_mac("AA:BB:CC:DD:EE:0F").unwrap();
+        assert_eq!(mac_to_address(mac), 0xAABBCCDDEE0F);
+        assert_eq!(address_to_mac(0xAABBCCDDEE0F), mac);
+        assert_eq!(
+            format_mac(&address_to_mac(mac_to_address(mac)), ":"),
+            "AA:BB:CC:DD:EE:0F"
+        );
+    }
+}
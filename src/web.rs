@@ -0,0 +1,195 @@
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+
+use crate::api::api::BluetoothServiceData;
+use crate::context::ProxyContext;
+use crate::utils::{address_to_mac, format_mac};
+
+/// The status page; it fills itself in from `/api/status`.
+pub const STATUS_PAGE: &str = include_str!("status.html");
+
+fn payloads(entries: &[BluetoothServiceData]) -> BTreeMap<&str, String> {
+    entries
+        .iter()
+        .map(|entry| (entry.uuid.as_str(), hex::encode(&entry.data)))
+        .collect()
+}
+
+fn devices(ctx: &ProxyContext) -> Vec<Value> {
+    ctx.tracker
+        .devices()
+        .into_iter()
+        .map(|seen| {
+            let advert = &seen.advert;
+            json!({
+                "address": format_mac(&address_to_mac(advert.address), ":"),
+                "address_type": if advert.address_type == 1 { "random" } else { "public" },
+                "name": String::from_utf8_lossy(&seen.name),
+                "rssi": advert.rssi,
+                "last_seen": seen.last_seen.elapsed().as_secs_f32(),
+                "service_uuids": advert.service_uuids,
+                "service_data": payloads(&advert.service_data),
+                "manufacturer_data": payloads(&advert.manufacturer_data),
+            })
+        })
+        .collect()
+}
+
+fn clients(ctx: &ProxyContext) -> Vec<Value> {
+    let clients = ctx.clients.lock().unwrap();
+    let mut peers: Vec<_> = clients.keys().collect();
+    peers.sort();
+    peers
+        .into_iter()
+        .map(|peer| {
+            let client = &clients[peer];
+            json!({
+                "address": peer.to_string(),
+                "name": client.name,
+                "connected_for": client.since.elapsed().as_secs(),
+                "bytes_sent": client.outbound.bytes_sent(),
+            })
+        })
+        .collect()
+}
+
+fn adapter(ctx: &ProxyContext) -> Value {
+    let settings = ctx.scan_settings();
+    json!({
+        "source": ctx.advertisement_source,
+        "bluetooth_mac": format_mac(&ctx.bt_mac, ":"),
+        "scanning": settings.is_scanning(),
+        "mode": settings.effective_mode().label().to_lowercase(),
+        "overridden_by_rule": settings.overrides != Default::default(),
+        "advertisements_per_second": ctx.tracker.adverts_per_second(),
+        "advertisements_received": ctx.tracker.adverts_received(),
+        "known_devices": ctx.tracker.known_devices(),
+        "rssi_noise_floor": ctx.tracker.rssi_noise_floor(),
+        "dbus_errors": ctx.ble_stats.dbus_errors.load(Ordering::Relaxed),
+        "discovery_restarts": ctx.ble_stats.discovery_restarts.load(Ordering::Relaxed),
+    })
+}
+
+/// The effective settings, as in the configuration dump; secrets are only
+/// reported as set or not.
+fn config(ctx: &ProxyContext) -> Value {
+    let mut watched: Vec<String> = ctx
+        .watched
+        .iter()
+        .map(|&a| format_mac(&address_to_mac(a), ":"))
+        .collect();
+    watched.sort();
+    json!({
+        "name": ctx.device_name(),
+        "version": ctx.version,
+        "build_time": ctx.build_time,
+        "mac": format_mac(&ctx.net_mac, ":"),
+        "api_port": ctx.port,
+        "encryption": ctx.encryption_key().is_some(),
+        "password": ctx.password.is_some(),
+        "keepalive_interval": ctx.keepalive_interval.as_secs(),
+        "keepalive_misses": ctx.keepalive_misses,
+        "max_clock_skew": ctx.max_clock_skew.map(|max| max.as_secs()),
+        "min_rssi": ctx.min_rssi(),
+        "rules": ctx.rules.len(),
+        "services": ctx.services.iter().map(|service| &service.name).collect::<Vec<_>>(),
+        "host_sensors": ctx.host_sensors,
+        "watch": watched,
+        "watch_timeout": ctx.watch_timeout.as_secs(),
+        "state_dir": ctx.state.dir(),
+    })
+}
+
+/// Everything the status page shows, for `/api/status`.
+pub fn status(ctx: &ProxyContext) -> String {
+    json!({
+        "uptime": ctx.started.elapsed().as_secs(),
+        "adapter": adapter(ctx),
+        "clients": clients(ctx),
+        "devices": devices(ctx),
+        "config": config(ctx),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::api::BluetoothLEAdvertisementResponse;
+
+    const HOSTILE: &str = "<img src=x onerror=alert(1)>\"';\n</script>";
+
+    #[test]
+    fn page_only_sets_text() {
+        // Names and payloads come from the air; none may be parsed as HTML
+        for sink in [
+            "innerHTML",
+            "outerHTML",
+            "insertAdjacentHTML",
+            "document.write",
+        ] {
+            assert!(!STATUS_PAGE.contains(sink), "{sink}");
+        }
+        assert!(STATUS_PAGE.contains("td.textContent = text"));
+    }
+
+    #[test]
+    fn status_reports_devices_as_heard() {
+        let ctx = ProxyContext::for_tests();
+        ctx.tracker.record(&BluetoothLEAdvertisementResponse {
+            address: 0xAABBCCDDEE01,
+            address_type: 1,
+            name: HOSTILE.as_bytes().to_vec(),
+            rssi: -70,
+            service_uuids: vec![HOSTILE.to_string()],
+            service_data: vec![BluetoothServiceData {
+                uuid: "0000181a-0000-1000-8000-00805f9b34fb".to_string(),
+                data: vec![0xa1, 0xb2],
+                ..Default::default()
+            }],
+            manufacturer_data: vec![BluetoothServiceData {
+                uuid: "76".to_string(),
+                data: b"<b>".to_vec(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        let status: Value = serde_json::from_str(&status(&ctx)).unwrap();
+
+        let device = &status["devices"][0];
+        assert_eq!(device["address"], "AA:BB:CC:DD:EE:01");
+        assert_eq!(device["address_type"], "random");
+        assert_eq!(device["name"], HOSTILE);
+        assert_eq!(device["rssi"], -70);
+        assert!(device["last_seen"].is_f64());
+        assert_eq!(device["service_uuids"], json!([HOSTILE]));
+        assert_eq!(
+            device["service_data"],
+            json!({"0000181a-0000-1000-8000-00805f9b34fb": "a1b2"})
+        );
+        // Payloads are hex, whatever bytes they carry
+        assert_eq!(device["manufacturer_data"], json!({"76": "3c623e"}));
+        assert_eq!(status["devices"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn status_has_every_section() {
+        let ctx = ProxyContext::for_tests();
+        let status: Value = serde_json::from_str(&status(&ctx)).unwrap();
+
+        assert!(status["uptime"].is_u64());
+        assert_eq!(status["clients"], json!([]));
+        assert_eq!(status["devices"], json!([]));
+        assert_eq!(status["adapter"]["bluetooth_mac"], "02:00:00:00:00:02");
+        assert_eq!(status["adapter"]["known_devices"], 0);
+        let config = &status["config"];
+        assert_eq!(config["name"], "Linux BT Proxy: test");
+        assert_eq!(config["mac"], "02:00:00:00:00:01");
+        assert_eq!(config["api_port"], 6053);
+        assert_eq!(config["encryption"], false);
+        assert_eq!(config["password"], false);
+        assert_eq!(config["watch"], json!([]));
+        assert_eq!(config["min_rssi"], Value::Null);
+    }
+}